@@ -1,37 +1,113 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use benchmarks_game::{rehnberger, biffle, thaumant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use benchmarks_game::{barnes_hut, body, parallel, rehnberger, biffle, soa, thaumant, NBodySimulator};
 
-pub fn nbody_benchmark(c: &mut Criterion) {
-
-    c.bench_function("biffle", |b| {
-        let mut biffle_bodies = biffle::STARTING_STATE;
-        biffle::offset_momentum(&mut biffle_bodies);
+fn bench_simulator<S: NBodySimulator>(c: &mut Criterion, name: &str, mut sim: S) {
+    c.bench_function(name, |b| {
+        sim.offset_momentum();
         b.iter(|| {
-            for _ in 0..1000 {
-                biffle::advance(&mut biffle_bodies);
-            }
+            sim.advance(1000, 0.01);
         })
     });
+}
 
-    c.bench_function("rehnberger", |b| {
-        let mut rehnberger_bodies = rehnberger::STARTING_STATE;
-        let mut rehnberger_sim = rehnberger::BodiesAdvance::new();
-        rehnberger::offset_momentum(&mut rehnberger_bodies);
-        b.iter(|| {
-            for _ in 0..1000 {
-                rehnberger_sim.advance(&mut rehnberger_bodies, 0.01);
-            }
-        })
-    });
+pub fn nbody_benchmark(c: &mut Criterion) {
+    bench_simulator(c, "biffle", biffle::Simulation::starting_state());
+    bench_simulator(c, "rehnberger", rehnberger::Simulation::starting_state());
+    bench_simulator(c, "soa", soa::Simulation::starting_state());
+    bench_simulator(c, "thaumant", thaumant::Simulation::starting_state());
+}
 
-    c.bench_function("thaumant", |b| {
-        let mut thaumant2_bodies = thaumant::STARTING_STATE;
-        thaumant::offset_momentum(&mut thaumant2_bodies);
+pub fn rehnberger_kernels_benchmark(c: &mut Criterion) {
+    use rehnberger::Kernel;
+    for &kernel in [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2].iter() {
+        if kernel.is_supported() {
+            let name = format!("rehnberger {:?}", kernel);
+            let sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel);
+            bench_simulator(c, &name, sim);
+        }
+    }
+}
+
+fn bench_large<S: NBodySimulator>(c: &mut Criterion, count: usize) {
+    let mut group = c.benchmark_group("asteroid belt");
+    group.sample_size(10);
+    group.bench_with_input(BenchmarkId::new(S::NAME, count), &count, |b, &count| {
+        let mut sim = S::from_bodies(&body::asteroid_belt(count));
         b.iter(|| {
-            thaumant::advance(&mut thaumant2_bodies, 1000);
+            sim.advance(1, 0.01);
         })
     });
+    group.finish();
+}
+
+pub fn large_benchmark(c: &mut Criterion) {
+    for &count in [100, 1000].iter() {
+        bench_large::<biffle::Simulation>(c, count);
+        bench_large::<rehnberger::Simulation>(c, count);
+        bench_large::<soa::Simulation>(c, count);
+        bench_large::<thaumant::Simulation>(c, count);
+        bench_large::<barnes_hut::Simulation>(c, count);
+    }
+}
+
+pub fn barnes_hut_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("barnes-hut");
+    group.sample_size(10);
+    for &count in [1000, 10_000, 100_000].iter() {
+        let id = BenchmarkId::new(format!("theta {}", barnes_hut::DEFAULT_THETA), count);
+        group.bench_with_input(id, &count, |b, &count| {
+            let mut sim = barnes_hut::Simulation::from_bodies(&body::asteroid_belt(count));
+            b.iter(|| {
+                sim.advance(1, 0.01);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Scaling of the parallel kernel from one thread to all cores, against the
+/// serial iterator kernel of `thaumant`.
+///
+/// thaumant buffers a vector and a magnitude for every pair, about 1.6 GB at
+/// 10 000 bodies, so it is only compared at 1000.
+pub fn parallel_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel");
+    group.sample_size(10);
+    for &count in [1000, 10_000].iter() {
+        let bodies = body::asteroid_belt(count);
+        if count <= 1000 {
+            group.bench_with_input(BenchmarkId::new("thaumant", count), &count, |b, _| {
+                let mut sim = thaumant::Simulation::from_bodies(&bodies);
+                b.iter(|| {
+                    sim.advance(1, 0.01);
+                })
+            });
+        }
+        let available = parallel::available_threads();
+        let mut threads = 1;
+        loop {
+            let id = BenchmarkId::new(format!("{} threads", threads), count);
+            group.bench_with_input(id, &count, |b, _| {
+                let mut sim = parallel::Simulation::from_bodies(&bodies).with_threads(threads);
+                b.iter(|| {
+                    sim.advance(1, 0.01);
+                })
+            });
+            if threads == available {
+                break;
+            }
+            threads = (threads * 2).min(available);
+        }
+    }
+    group.finish();
 }
 
-criterion_group!(benches, nbody_benchmark);
+criterion_group!(
+    benches,
+    nbody_benchmark,
+    rehnberger_kernels_benchmark,
+    large_benchmark,
+    barnes_hut_benchmark,
+    parallel_benchmark
+);
 criterion_main!(benches);
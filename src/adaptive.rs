@@ -0,0 +1,417 @@
+//! Adaptive time-stepping with error control.
+//!
+//! The fixed-step implementations take the same `dt` however close two bodies
+//! come, so a close approach can throw a body out of the system. This module
+//! integrates implementation-neutral `BodyState`s with the embedded
+//! Dormand–Prince 5(4) Runge–Kutta pair instead: each step also yields a
+//! fourth-order estimate, and the difference between the two decides whether
+//! the step is accepted and how long the next one should be.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::BodyState;
+
+// Dormand–Prince coupling coefficients and fifth-order weights. The last row
+// of `A` doubles as the weights, so the last stage of one step is the first
+// stage of the next.
+const A: [[f64; 6]; 7] = [
+    [0., 0., 0., 0., 0., 0.],
+    [1. / 5., 0., 0., 0., 0., 0.],
+    [3. / 40., 9. / 40., 0., 0., 0., 0.],
+    [44. / 45., -56. / 15., 32. / 9., 0., 0., 0.],
+    [19372. / 6561., -25360. / 2187., 64448. / 6561., -212. / 729., 0., 0.],
+    [9017. / 3168., -355. / 33., 46732. / 5247., 49. / 176., -5103. / 18656., 0.],
+    [35. / 384., 0., 500. / 1113., 125. / 192., -2187. / 6784., 11. / 84.],
+];
+// Difference between the fifth- and fourth-order weights.
+const E: [f64; 7] = [
+    71. / 57600.,
+    0.,
+    -71. / 16695.,
+    71. / 1920.,
+    -17253. / 339200.,
+    22. / 525.,
+    -1. / 40.,
+];
+
+/// Safety factor and bounds on the change of step size between two steps.
+const SAFETY: f64 = 0.9;
+const MIN_FACTOR: f64 = 0.2;
+const MAX_FACTOR: f64 = 5.;
+
+/// Dormand–Prince integrator settings.
+///
+/// Gravitation is not softened: close approaches are handled by shortening
+/// the step instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DormandPrince {
+    tolerance: f64,
+    initial_step: f64,
+    min_step: f64,
+}
+
+/// Outcome of an adaptive run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Number of accepted steps.
+    pub steps: usize,
+    /// Number of steps rejected and retried with a shorter step size.
+    pub rejected: usize,
+    /// Length of each accepted step, in order; negative when integrating
+    /// backwards.
+    pub step_sizes: Vec<f64>,
+}
+
+impl Report {
+    /// Shortest accepted step size.
+    pub fn min_step(&self) -> f64 {
+        self.step_sizes.iter().map(|dt| dt.abs()).fold(f64::INFINITY, f64::min)
+    }
+
+    /// Longest accepted step size.
+    pub fn max_step(&self) -> f64 {
+        self.step_sizes.iter().map(|dt| dt.abs()).fold(0., f64::max)
+    }
+}
+
+/// The step size needed to meet the tolerance fell below the minimum, usually
+/// because two bodies (nearly) collided.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepTooSmall {
+    /// Time reached before the failing step.
+    pub time: f64,
+    /// Step size that was still too long.
+    pub step: f64,
+    /// Steps accepted up to `time`.
+    pub report: Report,
+}
+
+impl fmt::Display for StepTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "step size {:e} below the minimum at t = {}", self.step, self.time)
+    }
+}
+
+impl Error for StepTooSmall {}
+
+impl DormandPrince {
+    /// Integrator keeping the local error of each step below `tolerance`,
+    /// relative to the magnitude of the positions and velocities.
+    pub fn new(tolerance: f64) -> DormandPrince {
+        assert!(tolerance > 0., "tolerance must be positive");
+        DormandPrince {
+            tolerance,
+            initial_step: 0.01,
+            min_step: 1e-12,
+        }
+    }
+
+    /// Sets the length of the first attempted step, `0.01` by default.
+    pub fn with_initial_step(self, initial_step: f64) -> DormandPrince {
+        assert!(initial_step > 0., "initial step must be positive");
+        DormandPrince { initial_step, ..self }
+    }
+
+    /// Sets the step size below which integration gives up, `1e-12` by default.
+    pub fn with_min_step(self, min_step: f64) -> DormandPrince {
+        assert!(min_step > 0. && min_step.is_finite(), "minimum step must be positive and finite");
+        DormandPrince { min_step, ..self }
+    }
+
+    /// Largest local error accepted per step, relative to the magnitude of
+    /// the positions and velocities.
+    pub fn tolerance(&self) -> f64 {
+        self.tolerance
+    }
+
+    /// Simulates `bodies` for `time` years, backwards when `time` is negative.
+    ///
+    /// The last step is shortened to land on `time` exactly. On error,
+    /// `bodies` holds the state reached before the failing step.
+    pub fn integrate(&self, bodies: &mut [BodyState], time: f64) -> Result<Report, StepTooSmall> {
+        let mut report = Report::default();
+        if bodies.is_empty() || time == 0. {
+            return Ok(report);
+        }
+
+        let direction = time.signum();
+        let n = bodies.len();
+        let mut state = State::from_bodies(bodies);
+        let mut stages = vec![State::zeroed(n); 7];
+        stages[0] = state.derivative(bodies);
+        let mut trial = State::zeroed(n);
+        let mut t = 0.;
+        let mut dt = self.initial_step.min(time.abs());
+
+        while (time - t) * direction > 0. {
+            let last = dt >= (time - t).abs();
+            if last {
+                dt = (time - t).abs();
+            }
+            let h = direction * dt;
+
+            for s in 1..7 {
+                trial.combine(&state, &stages, &A[s][..s], h);
+                stages[s] = trial.derivative(bodies);
+            }
+            let error = state.error(&trial, &stages, h, self.tolerance);
+
+            if error <= 1. {
+                state = trial.clone();
+                stages[0] = stages[6].clone();
+                t = if last { time } else { t + h };
+                report.steps += 1;
+                report.step_sizes.push(h);
+            } else {
+                report.rejected += 1;
+            }
+            if last && error <= 1. {
+                break;
+            }
+
+            // A non-finite state, e.g. from two bodies at the same position,
+            // gives a NaN error; shrink the step as far as allowed then.
+            let factor = if error.is_nan() {
+                MIN_FACTOR
+            } else {
+                (SAFETY * error.powf(-0.2)).clamp(MIN_FACTOR, MAX_FACTOR)
+            };
+            let next = dt * if error <= 1. { factor } else { factor.min(1.) };
+            if next < self.min_step {
+                state.store(bodies);
+                return Err(StepTooSmall { time: t, step: next, report });
+            }
+            dt = next;
+        }
+
+        state.store(bodies);
+        Ok(report)
+    }
+}
+
+/// Positions followed by velocities, or their time derivatives.
+#[derive(Clone, Debug)]
+struct State(Vec<[f64; 3]>);
+
+impl State {
+    fn zeroed(n: usize) -> State {
+        State(vec![[0.; 3]; 2 * n])
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> State {
+        let positions = bodies.iter().map(|body| body.position);
+        let velocities = bodies.iter().map(|body| body.velocity);
+        State(positions.chain(velocities).collect())
+    }
+
+    fn store(&self, bodies: &mut [BodyState]) {
+        let (positions, velocities) = self.0.split_at(bodies.len());
+        for (body, (position, velocity)) in bodies.iter_mut().zip(positions.iter().zip(velocities)) {
+            body.position = *position;
+            body.velocity = *velocity;
+        }
+    }
+
+    /// Velocities and gravitational accelerations; `bodies` supplies the masses.
+    fn derivative(&self, bodies: &[BodyState]) -> State {
+        let n = bodies.len();
+        let (positions, velocities) = self.0.split_at(n);
+        let mut derivative = State::zeroed(n);
+        derivative.0[..n].copy_from_slice(velocities);
+
+        let accelerations = &mut derivative.0[n..];
+        for i in 0..n {
+            for j in i + 1..n {
+                let mut d = [0.; 3];
+                for m in 0..3 {
+                    d[m] = positions[i][m] - positions[j][m];
+                }
+                let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+                let mag = 1. / (distance_squared * distance_squared.sqrt());
+                for m in 0..3 {
+                    accelerations[i][m] -= d[m] * bodies[j].mass * mag;
+                    accelerations[j][m] += d[m] * bodies[i].mass * mag;
+                }
+            }
+        }
+        derivative
+    }
+
+    /// `start + h * sum(weights[s] * stages[s])`.
+    fn combine(&mut self, start: &State, stages: &[State], weights: &[f64], h: f64) {
+        for (k, value) in self.0.iter_mut().enumerate() {
+            for (m, component) in value.iter_mut().enumerate() {
+                let mut sum = 0.;
+                for (stage, weight) in stages.iter().zip(weights) {
+                    sum += weight * stage.0[k][m];
+                }
+                *component = start.0[k][m] + h * sum;
+            }
+        }
+    }
+
+    /// Root-mean-square of the embedded error estimate between `self` and the
+    /// step's result `next`, scaled so that 1 is exactly the tolerance.
+    fn error(&self, next: &State, stages: &[State], h: f64, tolerance: f64) -> f64 {
+        let mut sum = 0.;
+        for (k, (start, end)) in self.0.iter().zip(&next.0).enumerate() {
+            for m in 0..3 {
+                let mut estimate = 0.;
+                for (stage, weight) in stages.iter().zip(E.iter()) {
+                    estimate += weight * stage.0[k][m];
+                }
+                let scale = tolerance * (1. + start[m].abs().max(end[m].abs()));
+                sum += (h * estimate / scale).powi(2);
+            }
+        }
+        (sum / (3 * self.0.len()) as f64).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::body;
+    use crate::biffle;
+    use crate::integrator::Integrator;
+    use crate::NBodySimulator;
+
+    fn energy(bodies: &[BodyState]) -> f64 {
+        let sim = biffle::Simulation::from_bodies(bodies);
+        sim.energy()
+    }
+
+    /// A light body on an orbit with eccentricity 0.99 around a solar mass,
+    /// starting at aphelion with a semi-major axis of 1 AU.
+    fn eccentric_orbit() -> Vec<BodyState> {
+        let eccentricity = 0.99;
+        let aphelion = 1. + eccentricity;
+        let speed = (biffle::SOLAR_MASS * (1. - eccentricity) / aphelion).sqrt();
+        vec![
+            BodyState {
+                name: "Sun".to_string(),
+                position: [0.; 3],
+                velocity: [0.; 3],
+                mass: biffle::SOLAR_MASS,
+            },
+            BodyState {
+                name: "Comet".to_string(),
+                position: [aphelion, 0., 0.],
+                velocity: [0., speed, 0.],
+                mass: 1e-12 * biffle::SOLAR_MASS,
+            },
+        ]
+    }
+
+    #[test]
+    fn stages_are_consistent() {
+        // Each row of `A` sums to the time at which its stage is evaluated.
+        let nodes = [0., 1. / 5., 3. / 10., 4. / 5., 8. / 9., 1., 1.];
+        for (s, row) in A.iter().enumerate() {
+            let sum: f64 = row.iter().sum();
+            assert!((sum - nodes[s]).abs() < 1e-15, "row {}", s);
+        }
+        assert!(E.iter().sum::<f64>().abs() < 1e-15);
+    }
+
+    #[test]
+    fn reaches_target_time() {
+        let mut bodies = body::starting_state();
+        let report = DormandPrince::new(1e-10).integrate(&mut bodies, 12.345).unwrap();
+
+        assert_eq!(report.steps, report.step_sizes.len());
+        assert!((report.step_sizes.iter().sum::<f64>() - 12.345).abs() < 1e-12);
+        assert_eq!(bodies[0].name, "Sun");
+    }
+
+    #[test]
+    fn matches_fixed_step() {
+        let mut adaptive = body::starting_state();
+        DormandPrince::new(1e-12).integrate(&mut adaptive, 10.).unwrap();
+
+        let mut fixed = biffle::Simulation::starting_state();
+        Integrator::Yoshida4.advance(&mut fixed, 10_000, 0.001);
+
+        for (a, b) in adaptive.iter().zip(fixed.bodies()) {
+            for m in 0..3 {
+                assert!((a.position[m] - b.position[m]).abs() < 1e-8, "{:?} != {:?}", a, b);
+                assert!((a.velocity[m] - b.velocity[m]).abs() < 1e-8, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn backwards_returns_to_start() {
+        let start = body::starting_state();
+        let mut bodies = start.clone();
+        let integrator = DormandPrince::new(1e-12);
+        integrator.integrate(&mut bodies, 5.).unwrap();
+        let report = integrator.integrate(&mut bodies, -5.).unwrap();
+
+        assert!(report.step_sizes.iter().all(|&dt| dt < 0.));
+        for (a, b) in start.iter().zip(&bodies) {
+            for m in 0..3 {
+                assert!((a.position[m] - b.position[m]).abs() < 1e-9, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn tighter_tolerance_takes_more_steps() {
+        let steps: Vec<usize> = [1e-6, 1e-9, 1e-12]
+            .iter()
+            .map(|&tolerance| {
+                let mut bodies = body::starting_state();
+                DormandPrince::new(tolerance).integrate(&mut bodies, 10.).unwrap().steps
+            })
+            .collect();
+        assert!(steps.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", steps);
+    }
+
+    #[test]
+    fn close_approach() {
+        // Three orbits, each passing within 0.01 AU of the Sun.
+        let start = eccentric_orbit();
+        let mut bodies = start.clone();
+        let report = DormandPrince::new(1e-10).integrate(&mut bodies, 3.).unwrap();
+
+        // Steps shrink by orders of magnitude around perihelion.
+        assert!(report.max_step() > 1000. * report.min_step(), "{:?}", report);
+        let drift = ((energy(&bodies) - energy(&start)) / energy(&start)).abs();
+        assert!(drift < 1e-6, "{}", drift);
+
+        // The same number of fixed steps loses the orbit at the first perihelion.
+        let mut fixed = biffle::Simulation::from_bodies(&start);
+        Integrator::Leapfrog.advance(&mut fixed, report.steps, 3. / report.steps as f64);
+        let fixed_drift = ((fixed.energy() - energy(&start)) / energy(&start)).abs();
+        assert!(fixed_drift > 1e3 * drift, "{} <= {}", fixed_drift, drift);
+    }
+
+    #[test]
+    fn short_last_step_succeeds() {
+        // The last step is a thousandth of the minimum, which only bounds the
+        // steps still to come.
+        let mut bodies = body::starting_state();
+        let integrator = DormandPrince::new(1e-6).with_min_step(1e-3);
+        let report = integrator.integrate(&mut bodies, 0.010_001).unwrap();
+        assert_eq!(report.steps, 2);
+        assert!((report.step_sizes[1] - 1e-6).abs() < 1e-15, "{:?}", report.step_sizes);
+    }
+
+    #[test]
+    #[should_panic(expected = "minimum step must be positive")]
+    fn zero_min_step_is_rejected() {
+        DormandPrince::new(1e-9).with_min_step(0.);
+    }
+
+    #[test]
+    fn collision_fails() {
+        let mut bodies = eccentric_orbit();
+        bodies[1].velocity = [0.; 3];
+        let error = DormandPrince::new(1e-10).integrate(&mut bodies, 1.).unwrap_err();
+
+        assert!(error.time > 0. && error.time < 1., "{}", error);
+        assert_eq!(error.report.steps, error.report.step_sizes.len());
+    }
+}
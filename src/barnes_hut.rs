@@ -0,0 +1,473 @@
+//! Barnes–Hut tree code, for systems too large for all-pairs interactions.
+//!
+//! Every kick sorts the bodies into an octree and, for each body, walks it
+//! from the root: a cell whose size is less than `θ` times its distance to
+//! the body acts as a single mass at its centre of mass, any other cell is
+//! opened. That takes about `N log N` interactions instead of `N²/2`. With
+//! `θ = 0` every cell is opened, and the forces are those of the all-pairs
+//! implementations up to the order of summation.
+//!
+//! Bodies are kept as `BodyState`s, and the energy is computed exactly, over
+//! all pairs.
+
+use crate::checkpoint::{parse_setting, parse_settings, softening_settings, unknown_setting};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
+/// Opening angle used by `Simulation::new`.
+pub const DEFAULT_THETA: f64 = 0.5;
+
+/// Cells are not split beyond this depth, so that bodies at the same position
+/// end up sharing a leaf instead of splitting it forever.
+const MAX_DEPTH: usize = 48;
+
+/// Cell of the octree.
+#[derive(Clone, Debug, Default)]
+struct Node {
+    /// Centre and half the side length of the cell.
+    centre: [f64; 3],
+    half_size: f64,
+    centre_of_mass: [f64; 3],
+    mass: f64,
+    /// Bodies in the cell, as a range of `Octree::order`.
+    first: usize,
+    end: usize,
+    /// Index of the first node after this cell's subtree. Nodes are stored in
+    /// depth-first order, so a cell's children, if any, start right after it.
+    next: usize,
+    leaf: bool,
+}
+
+/// Octree over the bodies' positions, rebuilt for every kick.
+#[derive(Clone, Debug, Default)]
+struct Octree {
+    nodes: Vec<Node>,
+    /// Body indices, grouped by cell.
+    order: Vec<usize>,
+}
+
+impl Octree {
+    fn build(&mut self, bodies: &[BodyState]) {
+        self.nodes.clear();
+        self.order.clear();
+        self.order.extend(0..bodies.len());
+        if bodies.is_empty() {
+            return;
+        }
+
+        let mut min = [f64::INFINITY; 3];
+        let mut max = [f64::NEG_INFINITY; 3];
+        for body in bodies {
+            for m in 0..3 {
+                min[m] = min[m].min(body.position[m]);
+                max[m] = max[m].max(body.position[m]);
+            }
+        }
+        let centre = [0, 1, 2].map(|m| 0.5 * (min[m] + max[m]));
+        let half_size = (0..3).map(|m| 0.5 * (max[m] - min[m])).fold(0., f64::max);
+        // Pad the root cell so that bodies on its faces fall inside.
+        let half_size = if half_size > 0. { half_size * (1. + 1e-9) } else { 1. };
+
+        let mut order = std::mem::take(&mut self.order);
+        self.insert(bodies, &mut order, 0, centre, half_size, 0);
+        self.order = order;
+    }
+
+    /// Appends the cell holding `order[first..]` and its subtree.
+    fn insert(
+        &mut self,
+        bodies: &[BodyState],
+        order: &mut [usize],
+        first: usize,
+        centre: [f64; 3],
+        half_size: f64,
+        depth: usize,
+    ) {
+        let index = self.nodes.len();
+        let mut node = Node {
+            centre,
+            half_size,
+            first,
+            end: first + order.len(),
+            ..Node::default()
+        };
+        for &i in order.iter() {
+            node.mass += bodies[i].mass;
+            for m in 0..3 {
+                node.centre_of_mass[m] += bodies[i].mass * bodies[i].position[m];
+            }
+        }
+        if node.mass > 0. {
+            node.centre_of_mass = node.centre_of_mass.map(|sum| sum / node.mass);
+        } else {
+            node.centre_of_mass = centre;
+        }
+        node.leaf = order.len() <= 1 || depth == MAX_DEPTH;
+        self.nodes.push(node);
+
+        if !self.nodes[index].leaf {
+            // Sort the bodies by octant, then build a child for each octant
+            // that has any.
+            let octant = |i: usize| {
+                let p = bodies[i].position;
+                (0..3).fold(0, |octant, m| octant | (((p[m] >= centre[m]) as usize) << m))
+            };
+            order.sort_unstable_by_key(|&i| octant(i));
+            let mut start = 0;
+            while start < order.len() {
+                let current = octant(order[start]);
+                let end = start + order[start..].iter().take_while(|&&i| octant(i) == current).count();
+                let quarter = 0.5 * half_size;
+                let child_centre = [0, 1, 2].map(|m| {
+                    if current & (1 << m) != 0 {
+                        centre[m] + quarter
+                    } else {
+                        centre[m] - quarter
+                    }
+                });
+                self.insert(bodies, &mut order[start..end], first + start, child_centre, quarter, depth + 1);
+                start = end;
+            }
+        }
+        self.nodes[index].next = self.nodes.len();
+    }
+
+    /// Gravitational acceleration of body `i` from all the others, softened
+    /// by the Plummer length `softening`.
+    fn acceleration(&self, bodies: &[BodyState], i: usize, theta: f64, softening: f64) -> [f64; 3] {
+        let position = bodies[i].position;
+        let mut acceleration = [0.; 3];
+        let mut pull = |towards: [f64; 3], mass: f64| {
+            let d = [0, 1, 2].map(|m| towards[m] - position[m]);
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2] + softening * softening;
+            let mag = mass / (distance_squared * distance_squared.sqrt());
+            for m in 0..3 {
+                acceleration[m] += d[m] * mag;
+            }
+        };
+
+        let mut k = 0;
+        while k < self.nodes.len() {
+            let node = &self.nodes[k];
+            if node.leaf {
+                for &j in &self.order[node.first..node.end] {
+                    if j != i {
+                        pull(bodies[j].position, bodies[j].mass);
+                    }
+                }
+                k = node.next;
+                continue;
+            }
+
+            let d = [0, 1, 2].map(|m| node.centre_of_mass[m] - position[m]);
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+            let size = 2. * node.half_size;
+            let inside = (0..3).all(|m| (position[m] - node.centre[m]).abs() <= node.half_size);
+            if !inside && size * size < theta * theta * distance_squared {
+                pull(node.centre_of_mass, node.mass);
+                k = node.next;
+            } else {
+                k += 1;
+            }
+        }
+        acceleration
+    }
+}
+
+/// Gravitational acceleration of each body, with opening angle `theta`.
+pub fn accelerations(bodies: &[BodyState], theta: f64) -> Vec<[f64; 3]> {
+    let mut tree = Octree::default();
+    tree.build(bodies);
+    (0..bodies.len()).map(|i| tree.acceleration(bodies, i, theta, 0.)).collect()
+}
+
+/// Compute the system energy exactly.
+pub fn compute_energy(bodies: &[BodyState]) -> f64 {
+    softened_energy(bodies, 0.)
+}
+
+/// Compute the system energy exactly, with the potential softened by
+/// `softening`.
+pub fn softened_energy(bodies: &[BodyState], softening: f64) -> f64 {
+    let mut energy = 0.;
+    add_energy(bodies, softening, &mut energy);
+    energy
+}
+
+/// Compute the system energy exactly, split into kinetic and potential parts.
+pub fn energy_breakdown(bodies: &[BodyState]) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.)
+}
+
+/// Compute the parts of the system energy exactly, with the potential
+/// softened by `softening`.
+pub fn softened_energy_breakdown(bodies: &[BodyState], softening: f64) -> EnergyBreakdown {
+    let mut breakdown = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut breakdown);
+    breakdown
+}
+
+fn add_energy<E: EnergyTerms>(bodies: &[BodyState], softening: f64, energy: &mut E) {
+    for (i, body) in bodies.iter().enumerate() {
+        let v = body.velocity;
+        energy.add_kinetic(0.5 * body.mass * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]));
+        for body2 in &bodies[i + 1..] {
+            let d = [0, 1, 2].map(|m| body.position[m] - body2.position[m]);
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2] + softening * softening;
+            energy.add_potential(-body.mass * body2.mass / distance_squared.sqrt());
+        }
+    }
+}
+
+/// Bodies and octree of a running simulation, behind the common
+/// `NBodySimulator` interface.
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    bodies: Vec<BodyState>,
+    theta: f64,
+    softening: f64,
+    tree: Octree,
+}
+
+impl Simulation {
+    /// Sets the opening angle; 0 opens every cell, larger values are faster
+    /// and less accurate.
+    pub fn with_theta(self, theta: f64) -> Simulation {
+        assert!(theta >= 0., "opening angle must not be negative");
+        Simulation { theta, ..self }
+    }
+
+    pub fn theta(&self) -> f64 {
+        self.theta
+    }
+
+    /// Softens gravitation with a Plummer length `softening`, 0 by default.
+    pub fn with_softening(self, softening: f64) -> Simulation {
+        Simulation { softening, ..self }
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.softening
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "barnes-hut";
+
+    type Bodies = Vec<BodyState>;
+
+    fn new(bodies: Vec<BodyState>) -> Simulation {
+        Simulation {
+            bodies,
+            theta: DEFAULT_THETA,
+            softening: 0.,
+            tree: Octree::default(),
+        }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::from_bodies(&crate::body::starting_state())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(bodies.to_vec())
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        self.bodies
+            .iter()
+            .map(|body| BodyState { name: String::new(), ..body.clone() })
+            .collect()
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        self.bodies = bodies.to_vec();
+    }
+
+    /// The opening angle and any softening, in exponent notation which reads
+    /// back to the same bits.
+    fn settings(&self) -> String {
+        match softening_settings(self.softening) {
+            softening if softening.is_empty() => format!("theta={:e}", self.theta),
+            softening => format!("theta={:e} {}", self.theta, softening),
+        }
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        for (key, value) in parse_settings(settings)? {
+            match key {
+                "theta" => {
+                    let theta: f64 = parse_setting(key, value)?;
+                    if theta.is_nan() || theta < 0. {
+                        return Err(format!("negative theta {:?}", value));
+                    }
+                    self.theta = theta;
+                }
+                "softening" => self.softening = parse_setting(key, value)?,
+                _ => return Err(unknown_setting(key)),
+            }
+        }
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        if let Some((sun, planets)) = self.bodies.split_first_mut() {
+            sun.velocity = [0.; 3];
+            for planet in planets {
+                for m in 0..3 {
+                    sun.velocity[m] -= planet.velocity[m] * planet.mass / crate::biffle::SOLAR_MASS;
+                }
+            }
+        }
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.kick(dt);
+            self.drift(dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.tree.build(&self.bodies);
+        let accelerations: Vec<[f64; 3]> = (0..self.bodies.len())
+            .map(|i| self.tree.acceleration(&self.bodies, i, self.theta, self.softening))
+            .collect();
+        for (body, acceleration) in self.bodies.iter_mut().zip(accelerations) {
+            for (velocity, acceleration) in body.velocity.iter_mut().zip(&acceleration) {
+                *velocity += dt * acceleration;
+            }
+        }
+    }
+
+    fn drift(&mut self, dt: f64) {
+        for body in &mut self.bodies {
+            for m in 0..3 {
+                body.position[m] += dt * body.velocity[m];
+            }
+        }
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.softening)
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.softening)
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.position).collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.velocity).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, body};
+
+    /// Accelerations from `biffle`'s all-pairs kick: a unit kick from rest
+    /// leaves each body moving at its acceleration.
+    fn all_pairs_accelerations(bodies: &[BodyState]) -> Vec<[f64; 3]> {
+        let mut at_rest: Vec<biffle::Body> = bodies
+            .iter()
+            .map(|body| biffle::Body::from(&BodyState { velocity: [0.; 3], ..body.clone() }))
+            .collect();
+        biffle::kick(&mut at_rest, 1., &mut biffle::Interactions::default());
+        at_rest.iter().map(|body| BodyState::from(body).velocity).collect()
+    }
+
+    /// Largest error of any acceleration, relative to the largest acceleration.
+    fn relative_error(exact: &[[f64; 3]], approximate: &[[f64; 3]]) -> f64 {
+        let norm = |a: &[f64; 3]| (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+        let scale = exact.iter().map(norm).fold(0., f64::max);
+        exact
+            .iter()
+            .zip(approximate)
+            .map(|(e, a)| norm(&[e[0] - a[0], e[1] - a[1], e[2] - a[2]]) / scale)
+            .fold(0., f64::max)
+    }
+
+    /// Bodies of equal mass spread through a ball, without a dominant one.
+    fn cluster(count: usize) -> Vec<BodyState> {
+        let golden = (5f64.sqrt() - 1.) / 2.;
+        (0..count)
+            .map(|i| {
+                let i = i as f64;
+                let radius = (i * golden * golden).fract().cbrt();
+                let z = 2. * (i * golden).fract() - 1.;
+                let angle = 2. * std::f64::consts::PI * i * golden;
+                let ring = (1. - z * z).sqrt();
+                BodyState {
+                    name: String::new(),
+                    position: [radius * ring * angle.cos(), radius * ring * angle.sin(), radius * z],
+                    velocity: [0.; 3],
+                    mass: 1. / count as f64,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn converges_to_all_pairs() {
+        for bodies in [cluster(300), body::asteroid_belt(300)].iter() {
+            let exact = all_pairs_accelerations(bodies);
+            let errors: Vec<f64> = [1., 0.5, 0.25, 0.1, 0.]
+                .iter()
+                .map(|&theta| relative_error(&exact, &accelerations(bodies, theta)))
+                .collect();
+
+            assert!(errors.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", errors);
+            assert!(errors[1] < 1e-1, "{:?}", errors);
+            assert!(errors[3] < 1e-3, "{:?}", errors);
+            assert!(errors[4] < 1e-13, "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn zero_theta_follows_biffle() {
+        let bodies = body::asteroid_belt(48);
+        let mut tree = Simulation::from_bodies(&bodies).with_theta(0.);
+        let mut all_pairs = biffle::Simulation::from_bodies(&bodies);
+        tree.offset_momentum();
+        all_pairs.offset_momentum();
+        tree.advance(100, 0.01);
+        all_pairs.advance(100, 0.01);
+
+        for (a, b) in tree.bodies().iter().zip(all_pairs.bodies()) {
+            for m in 0..3 {
+                assert!((a.position[m] - b.position[m]).abs() < 1e-12, "{:?} != {:?}", a, b);
+                assert!((a.velocity[m] - b.velocity[m]).abs() < 1e-12, "{:?} != {:?}", a, b);
+            }
+        }
+        assert!((tree.energy() - all_pairs.energy()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn jovian_energy() {
+        let mut sim = Simulation::starting_state().with_theta(0.);
+        sim.offset_momentum();
+        let before = sim.energy();
+        sim.advance(1000, 0.01);
+        assert_eq!(format!("{:.9}\n{:.9}\n", before, sim.energy()), "-0.169075164\n-0.169087605\n");
+    }
+
+    #[test]
+    fn degenerate_systems() {
+        assert!(accelerations(&[], 0.5).is_empty());
+
+        let lone = cluster(1);
+        assert_eq!(accelerations(&lone, 0.5), [[0.; 3]]);
+
+        // Coincident bodies share a leaf at the maximum depth instead of
+        // splitting it forever; the others still feel their pull.
+        let mut bodies = cluster(10);
+        bodies[3].position = bodies[2].position;
+        let accelerations = accelerations(&bodies, 0.5);
+        assert!(accelerations[0].iter().all(|a| a.is_finite()));
+    }
+}
@@ -4,6 +4,10 @@
 //! like. It does the math in the simplest way I could come up with, and relies
 //! on the compiler to make it fast.
 
+use crate::checkpoint::{parse_softening, softening_settings};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
 /// State of a single body (sun or planet) in the solar system.
 #[derive(Clone, Debug)]
 pub struct Body {
@@ -12,15 +16,33 @@ pub struct Body {
     mass: f64,
 }
 
-/// Number of bodies modeled in the simulation.
+impl From<&BodyState> for Body {
+    fn from(state: &BodyState) -> Body {
+        Body {
+            position: state.position,
+            velocity: state.velocity,
+            mass: state.mass,
+        }
+    }
+}
+
+impl From<&Body> for BodyState {
+    fn from(body: &Body) -> BodyState {
+        BodyState {
+            name: String::new(),
+            position: body.position,
+            velocity: body.velocity,
+            mass: body.mass,
+        }
+    }
+}
+
+/// Number of bodies in the starting state.
 pub const BODIES_COUNT: usize = 5;
 
-pub const SOLAR_MASS: f64 = (4. * std::f64::consts::PI * std::f64::consts::PI);
+pub const SOLAR_MASS: f64 = 4. * std::f64::consts::PI * std::f64::consts::PI;
 pub const DAYS_PER_YEAR: f64 = 365.24;
 
-/// Number of body-body interactions.
-pub const INTERACTIONS: usize = BODIES_COUNT * (BODIES_COUNT - 1) / 2;
-
 /// Initial state of the simulation.
 pub const STARTING_STATE: [Body; BODIES_COUNT] = [
     // Sun
@@ -92,66 +114,117 @@ pub fn sqr(x: f64) -> f64 {
     x * x
 }
 
-/// Steps the simulation forward by one time-step.
-pub fn advance(bodies: &mut [Body; BODIES_COUNT]) {
-    // Compute point-to-point vectors between each unique pair of bodies.
-    // Note: this array is large enough that computing it mutable and returning
-    // it from a block, as I did with magnitudes below, generates a memcpy.
-    // Sigh. So I'll leave it mutable.
-    let mut position_deltas = [[0.; 3]; INTERACTIONS];
-    {
-        let mut k = 0;
-
-        for i in 0..BODIES_COUNT - 1 {
-            for j in i + 1..BODIES_COUNT {
-                for (m, pd) in position_deltas[k].iter_mut().enumerate() {
-                    *pd = bodies[i].position[m] - bodies[j].position[m];
+/// Steps the simulation forward by one time-step of length `dt`, with the
+/// buffers of `interactions`.
+pub fn advance(bodies: &mut [Body], dt: f64, interactions: &mut Interactions) {
+    kick(bodies, dt, interactions);
+    drift(bodies, dt);
+}
+
+/// Applies the gravitation between every pair of bodies to their velocities
+/// over a time-step `dt`, with the buffers of `interactions`.
+pub fn kick(bodies: &mut [Body], dt: f64, interactions: &mut Interactions) {
+    interactions.kick(bodies, dt);
+}
+
+/// Buffers for the vectors and magnitudes between each unique pair of bodies,
+/// reused across time-steps, and the Plummer softening length added to every
+/// distance.
+///
+/// The default buffers are empty, grow to the number of bodies on the first
+/// kick, and leave gravitation unsoftened.
+#[derive(Clone, Debug, Default)]
+pub struct Interactions {
+    position_deltas: Vec<[f64; 3]>,
+    magnitudes: Vec<f64>,
+    softening: f64,
+}
+
+impl Interactions {
+    fn kick(&mut self, bodies: &mut [Body], dt: f64) {
+        let bodies_count = bodies.len();
+
+        // Number of body-body interactions.
+        let interactions = bodies_count * bodies_count.saturating_sub(1) / 2;
+        self.position_deltas.resize(interactions, [0.; 3]);
+        self.magnitudes.resize(interactions, 0.);
+        let position_deltas = &mut self.position_deltas;
+
+        // Compute point-to-point vectors between each unique pair of bodies.
+        {
+            let mut k = 0;
+
+            for i in 0..bodies_count {
+                for j in i + 1..bodies_count {
+                    for (m, pd) in position_deltas[k].iter_mut().enumerate() {
+                        *pd = bodies[i].position[m] - bodies[j].position[m];
+                    }
+                    k += 1;
                 }
-                k += 1;
             }
         }
-    }
 
-    // Compute the `1/d^3` magnitude between each pair of bodies.
-    let magnitudes = {
-        let mut magnitudes = [0.; INTERACTIONS];
-        for (i, mag) in magnitudes.iter_mut().enumerate() {
-            let distance_squared = sqr(position_deltas[i][0])
-                + sqr(position_deltas[i][1])
-                + sqr(position_deltas[i][2]);
-
-            *mag = 0.01 / (distance_squared * distance_squared.sqrt());
+        // Compute the `1/d^3` magnitude between each pair of bodies, leaving
+        // out the softening when there is none.
+        if self.softening != 0. {
+            magnitudes::<true>(position_deltas, &mut self.magnitudes, dt, self.softening);
+        } else {
+            magnitudes::<false>(position_deltas, &mut self.magnitudes, dt, 0.);
         }
-        magnitudes
-    };
+        let magnitudes = &self.magnitudes;
 
-    // Apply every other body's gravitation to each body's velocity.
-    {
-        let mut k = 0;
-        for i in 0..BODIES_COUNT - 1 {
-            for j in i + 1..BODIES_COUNT {
-                let i_mass_mag = bodies[i].mass * magnitudes[k];
-                let j_mass_mag = bodies[j].mass * magnitudes[k];
-                for (m, pd) in position_deltas[k].iter().enumerate() {
-                    bodies[i].velocity[m] -= *pd * j_mass_mag;
-                    bodies[j].velocity[m] += *pd * i_mass_mag;
+        // Apply every other body's gravitation to each body's velocity.
+        {
+            let mut k = 0;
+            for i in 0..bodies_count {
+                for j in i + 1..bodies_count {
+                    let i_mass_mag = bodies[i].mass * magnitudes[k];
+                    let j_mass_mag = bodies[j].mass * magnitudes[k];
+                    for (m, pd) in position_deltas[k].iter().enumerate() {
+                        bodies[i].velocity[m] -= *pd * j_mass_mag;
+                        bodies[j].velocity[m] += *pd * i_mass_mag;
+                    }
+                    k += 1;
                 }
-                k += 1;
             }
         }
     }
+}
+
+/// Computes the `dt/d^3` magnitude of each of `position_deltas`, with the
+/// distance softened by `softening` if `SOFTENED`.
+fn magnitudes<const SOFTENED: bool>(
+    position_deltas: &[[f64; 3]],
+    magnitudes: &mut [f64],
+    dt: f64,
+    softening: f64,
+) {
+    for (mag, position_delta) in magnitudes.iter_mut().zip(position_deltas) {
+        let mut distance_squared =
+            sqr(position_delta[0]) + sqr(position_delta[1]) + sqr(position_delta[2]);
+        if SOFTENED {
+            distance_squared += sqr(softening);
+        }
+
+        *mag = dt / (distance_squared * distance_squared.sqrt());
+    }
+}
 
-    // Update each body's position.
+/// Moves each body along its velocity over a time-step `dt`.
+pub fn drift(bodies: &mut [Body], dt: f64) {
     for body in bodies {
         for (m, pos) in body.position.iter_mut().enumerate() {
-            *pos += 0.01 * body.velocity[m];
+            *pos += dt * body.velocity[m];
         }
     }
 }
 
 /// Adjust the Sun's velocity to offset system momentum.
-pub fn offset_momentum(bodies: &mut [Body; BODIES_COUNT]) {
-    let (sun, planets) = bodies.split_first_mut().unwrap();
+pub fn offset_momentum(bodies: &mut [Body]) {
+    let (sun, planets) = match bodies.split_first_mut() {
+        Some(split) => split,
+        None => return,
+    };
     sun.velocity = [0.; 3];
     for planet in planets {
         for m in 0..3 {
@@ -161,24 +234,137 @@ pub fn offset_momentum(bodies: &mut [Body; BODIES_COUNT]) {
 }
 
 /// Print the system energy.
-pub fn compute_energy(bodies: &mut [Body; BODIES_COUNT]) -> f64 {
+pub fn compute_energy(bodies: &[Body]) -> f64 {
+    softened_energy(bodies, 0.)
+}
+
+/// Compute the system energy with the potential softened by `softening`.
+pub fn softened_energy(bodies: &[Body], softening: f64) -> f64 {
     let mut energy = 0.;
+    add_energy(bodies, softening, &mut energy);
+    energy
+}
+
+/// Compute the system energy, split into kinetic and potential parts.
+pub fn energy_breakdown(bodies: &[Body]) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.)
+}
+
+/// Compute the parts of the system energy, with the potential softened by
+/// `softening`.
+pub fn softened_energy_breakdown(bodies: &[Body], softening: f64) -> EnergyBreakdown {
+    let mut breakdown = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut breakdown);
+    breakdown
+}
+
+fn add_energy<E: EnergyTerms>(bodies: &[Body], softening: f64, energy: &mut E) {
     for (i, body) in bodies.iter().enumerate() {
         // Add the kinetic energy for each body.
-        energy += 0.5
+        let kinetic = 0.5
             * body.mass
             * (sqr(body.velocity[0]) + sqr(body.velocity[1]) + sqr(body.velocity[2]));
+        energy.add_kinetic(kinetic);
 
         // Add the potential energy between this body and every other body.
-        for body2 in &bodies[i + 1..BODIES_COUNT] {
-            energy -= body.mass * body2.mass
-                / f64::sqrt(
-                    sqr(body.position[0] - body2.position[0])
-                        + sqr(body.position[1] - body2.position[1])
-                        + sqr(body.position[2] - body2.position[2]),
-                );
+        for body2 in &bodies[i + 1..] {
+            energy.add_potential(
+                -body.mass * body2.mass
+                    / f64::sqrt(
+                        sqr(body.position[0] - body2.position[0])
+                            + sqr(body.position[1] - body2.position[1])
+                            + sqr(body.position[2] - body2.position[2])
+                            + sqr(softening),
+                    ),
+            );
         }
     }
+}
 
-    energy
+/// Bodies of a running simulation, behind the common `NBodySimulator` interface.
+pub struct Simulation {
+    bodies: Vec<Body>,
+    interactions: Interactions,
+}
+
+impl Simulation {
+    /// Softens gravitation with a Plummer length `softening`, 0 by default.
+    pub fn with_softening(mut self, softening: f64) -> Simulation {
+        self.interactions.softening = softening;
+        self
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.interactions.softening
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "biffle";
+
+    type Bodies = Vec<Body>;
+
+    fn new(bodies: Self::Bodies) -> Simulation {
+        Simulation { bodies, interactions: Interactions::default() }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::new(STARTING_STATE.to_vec())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(bodies.iter().map(Body::from).collect())
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        self.bodies.iter().map(BodyState::from).collect()
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        self.bodies = bodies.iter().map(Body::from).collect();
+    }
+
+    fn settings(&self) -> String {
+        softening_settings(self.softening())
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        self.interactions.softening = parse_softening(settings)?;
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.interactions.kick(&mut self.bodies, dt);
+            drift(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.interactions.kick(&mut self.bodies, dt);
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, dt);
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.softening())
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.softening())
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.position).collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.velocity).collect()
+    }
 }
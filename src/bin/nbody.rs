@@ -0,0 +1,116 @@
+//! Runs the simulation the way the Benchmarks Game harness does.
+//!
+//! ```text
+//! nbody <steps> [--impl biffle|rehnberger|soa|thaumant]
+//! ```
+//!
+//! Prints the energy of the Jovian planets before and after `steps` steps, in
+//! the format of the reference output.
+
+use std::env;
+use std::process;
+
+use benchmarks_game::{biffle, reference_output, rehnberger, soa, thaumant, NBodySimulator};
+
+const USAGE: &str = "usage: nbody <steps> [--impl biffle|rehnberger|soa|thaumant]";
+
+/// Implementation to run, selected by `--impl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Implementation {
+    Biffle,
+    Rehnberger,
+    Soa,
+    Thaumant,
+}
+
+impl Implementation {
+    fn parse(name: &str) -> Result<Implementation, String> {
+        match name {
+            "biffle" => Ok(Implementation::Biffle),
+            "rehnberger" => Ok(Implementation::Rehnberger),
+            "soa" => Ok(Implementation::Soa),
+            "thaumant" => Ok(Implementation::Thaumant),
+            _ => Err(format!("unknown implementation: {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    steps: usize,
+    implementation: Implementation,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut steps = None;
+    let mut implementation = Implementation::Rehnberger;
+
+    while let Some(arg) = args.next() {
+        if arg == "--impl" {
+            let name = args.next().ok_or("missing value for --impl")?;
+            implementation = Implementation::parse(&name)?;
+        } else if let Some(name) = arg.strip_prefix("--impl=") {
+            implementation = Implementation::parse(name)?;
+        } else if steps.is_none() {
+            steps = Some(arg.parse().map_err(|_| format!("invalid step count: {}", arg))?);
+        } else {
+            return Err(format!("unexpected argument: {}", arg));
+        }
+    }
+
+    Ok(Options {
+        steps: steps.ok_or("missing step count")?,
+        implementation,
+    })
+}
+
+fn run<S: NBodySimulator>(steps: usize) {
+    print!("{}", reference_output::<S>(steps));
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("nbody: {}", message);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    match options.implementation {
+        Implementation::Biffle => run::<biffle::Simulation>(options.steps),
+        Implementation::Rehnberger => run::<rehnberger::Simulation>(options.steps),
+        Implementation::Soa => run::<soa::Simulation>(options.steps),
+        Implementation::Thaumant => run::<thaumant::Simulation>(options.steps),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_steps_and_implementation() {
+        let expected = Options { steps: 1000, implementation: Implementation::Biffle };
+        assert_eq!(parse(&["1000", "--impl", "biffle"]), Ok(expected));
+        let expected = Options { steps: 50, implementation: Implementation::Thaumant };
+        assert_eq!(parse(&["--impl=thaumant", "50"]), Ok(expected));
+        let expected = Options { steps: 7, implementation: Implementation::Rehnberger };
+        assert_eq!(parse(&["7"]), Ok(expected));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-5"]).is_err());
+        assert!(parse(&["ten"]).is_err());
+        assert!(parse(&["10", "20"]).is_err());
+        assert!(parse(&["10", "--impl"]).is_err());
+        assert!(parse(&["10", "--impl", "fortran"]).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Implementation-neutral description of a body.
+//!
+//! Every implementation converts its own `Body` to and from `BodyState`, so the
+//! same system can be fed into each of them and the results compared field by
+//! field.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::biffle;
+
+/// State of a single body, independent of any implementation's layout.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyState {
+    /// Name of the body; empty when converted from an implementation's `Body`.
+    pub name: String,
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+    pub mass: f64,
+}
+
+/// Names of the bodies in the starting state, in order.
+pub const STARTING_NAMES: [&str; biffle::BODIES_COUNT] =
+    ["Sun", "Jupiter", "Saturn", "Uranus", "Neptune"];
+
+/// The Sun and the Jovian planets, as in the implementations' `STARTING_STATE`.
+pub fn starting_state() -> Vec<BodyState> {
+    biffle::STARTING_STATE
+        .iter()
+        .zip(STARTING_NAMES.iter())
+        .map(|(body, name)| BodyState {
+            name: name.to_string(),
+            ..body.into()
+        })
+        .collect()
+}
+
+/// The Sun and `count - 1` asteroids on circular orbits between 2.2 and 3.3 AU.
+///
+/// Positions are spread deterministically, so the same `count` always yields the
+/// same system.
+pub fn asteroid_belt(count: usize) -> Vec<BodyState> {
+    let golden = (5f64.sqrt() - 1.) / 2.;
+    let sun = BodyState {
+        name: "Sun".to_string(),
+        position: [0.; 3],
+        velocity: [0.; 3],
+        mass: biffle::SOLAR_MASS,
+    };
+    let asteroids = (1..count).map(|i| {
+        let i = i as f64;
+        let radius = 2.2 + 1.1 * (i * golden).fract();
+        let angle = 2. * std::f64::consts::PI * (i * golden * golden).fract();
+        let speed = (biffle::SOLAR_MASS / radius).sqrt();
+        BodyState {
+            name: format!("Asteroid {}", i),
+            position: [radius * angle.cos(), radius * angle.sin(), 0.01 * i.sin()],
+            velocity: [-speed * angle.sin(), speed * angle.cos(), 0.],
+            mass: 1e-10 * biffle::SOLAR_MASS,
+        }
+    });
+    std::iter::once(sun).chain(asteroids).take(count).collect()
+}
+
+/// The bundled description of the Sun and the Jovian planets, in the format
+/// read by `parse_system`.
+///
+/// It has the digits of the C programs, as `rehnberger::STARTING_STATE` does;
+/// `biffle` and `thaumant` shorten Jupiter's and Neptune's x coordinates to
+/// values 1 and 3 units in the last place away.
+pub const JOVIAN_SYSTEM: &str = include_str!("../data/jovian.txt");
+
+/// Why a system description could not be loaded.
+#[derive(Debug)]
+pub enum SystemError {
+    Io(io::Error),
+    /// A line is not a valid body; `line` counts from 1.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for SystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemError::Io(error) => write!(f, "{}", error),
+            SystemError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for SystemError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SystemError::Io(error) => Some(error),
+            SystemError::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for SystemError {
+    fn from(error: io::Error) -> SystemError {
+        SystemError::Io(error)
+    }
+}
+
+/// Parses a system description.
+///
+/// Each line holds one body: its name, its mass in solar masses, its position
+/// `x y z` in AU and its velocity `x y z` in AU per day, separated by
+/// whitespace. Blank lines and everything after a `#` are ignored.
+///
+/// Masses and velocities are converted to the units of the implementations
+/// the same way their `STARTING_STATE`s are written, so the result converts
+/// into any implementation's bodies with `Body::from` or
+/// `NBodySimulator::from_bodies`.
+pub fn parse_system(text: &str) -> Result<Vec<BodyState>, SystemError> {
+    let mut bodies = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| SystemError::Parse { line: index + 1, message };
+        let content = line.split('#').next().unwrap_or("");
+        let fields: Vec<&str> = content.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() != 8 {
+            return Err(error(format!("expected 8 fields, found {}", fields.len())));
+        }
+
+        let mut numbers = [0.; 7];
+        for (number, field) in numbers.iter_mut().zip(&fields[1..]) {
+            *number = field.parse().map_err(|_| error(format!("invalid number: {}", field)))?;
+        }
+        let [mass, x, y, z, vx, vy, vz] = numbers;
+        bodies.push(BodyState {
+            name: fields[0].to_string(),
+            position: [x, y, z],
+            velocity: [
+                vx * biffle::DAYS_PER_YEAR,
+                vy * biffle::DAYS_PER_YEAR,
+                vz * biffle::DAYS_PER_YEAR,
+            ],
+            mass: mass * biffle::SOLAR_MASS,
+        });
+    }
+    Ok(bodies)
+}
+
+/// Reads and parses the system description in the file at `path`.
+pub fn load_system<P: AsRef<Path>>(path: P) -> Result<Vec<BodyState>, SystemError> {
+    parse_system(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{rehnberger, thaumant, NBodySimulator};
+
+    /// Components of `actual` that differ from `expected`, as
+    /// `(body, component, ulps)` with the position in components 0 to 2, the
+    /// velocity in 3 to 5 and the mass in 6, and the difference in units in
+    /// the last place.
+    fn differences<B>(expected: &[BodyState], actual: &[B]) -> Vec<(usize, usize, u64)>
+    where
+        for<'a> BodyState: From<&'a B>,
+    {
+        assert_eq!(expected.len(), actual.len());
+        let mut differences = Vec::new();
+        for (i, (expected, actual)) in expected.iter().zip(actual.iter().map(BodyState::from)).enumerate() {
+            let expected = expected.position.iter().chain(&expected.velocity).chain(Some(&expected.mass));
+            let actual: Vec<f64> = actual.position.iter().chain(&actual.velocity).chain(Some(&actual.mass)).cloned().collect();
+            for (k, (expected, actual)) in expected.zip(actual).enumerate() {
+                if *expected != actual {
+                    let ulps = (expected.to_bits() as i64 - actual.to_bits() as i64).unsigned_abs();
+                    differences.push((i, k, ulps));
+                }
+            }
+        }
+        differences
+    }
+
+    #[test]
+    fn jovian_file_matches_constants() {
+        let system = parse_system(JOVIAN_SYSTEM).unwrap();
+        let names: Vec<&str> = system.iter().map(|body| body.name.as_str()).collect();
+        assert_eq!(names, STARTING_NAMES);
+
+        // The file has the digits of the `gcc #4` program, as rehnberger does.
+        assert_eq!(differences(&system, &rehnberger::STARTING_STATE), []);
+
+        // biffle and thaumant shorten Jupiter's and Neptune's x coordinates to
+        // values 1 and 3 ulps away.
+        assert_eq!(differences(&system, &biffle::STARTING_STATE), [(1, 0, 1), (4, 0, 3)]);
+        assert_eq!(differences(&system, &thaumant::STARTING_STATE), [(1, 0, 1), (4, 0, 3)]);
+
+        // Loading produces each implementation's bodies.
+        let rehnberger_bodies: Vec<rehnberger::Body> = system.iter().map(rehnberger::Body::from).collect();
+        assert_eq!(differences(&system, &rehnberger_bodies), []);
+        let thaumant_bodies: Vec<thaumant::Body> = system.iter().map(thaumant::Body::from).collect();
+        assert_eq!(differences(&system, &thaumant_bodies), []);
+    }
+
+    #[test]
+    fn jovian_file_gives_reference_output() {
+        let mut sim = crate::soa::Simulation::from_bodies(&parse_system(JOVIAN_SYSTEM).unwrap());
+        sim.offset_momentum();
+        let before = sim.energy();
+        sim.advance(1000, 0.01);
+        assert_eq!(format!("{:.9}\n{:.9}\n", before, sim.energy()), "-0.169075164\n-0.169087605\n");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text| match parse_system(text) {
+            Err(SystemError::Parse { line, .. }) => line,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(error("Sun 1 0 0 0 0 0"), 1);
+        assert_eq!(error("# comment\n\nSun 1 0 0 0 0 0 zero"), 3);
+        assert_eq!(error("Sun 1 0 0 0 0 0 0 0"), 1);
+        assert!(parse_system("").unwrap().is_empty());
+        assert!(matches!(load_system("no/such/system.txt"), Err(SystemError::Io(_))));
+    }
+
+    #[test]
+    fn comments_and_units() {
+        let system = parse_system("  Comet 2e-10  1 2 3  0.5 0 -1  # trailing comment\n").unwrap();
+        assert_eq!(system.len(), 1);
+        assert_eq!(system[0].name, "Comet");
+        assert_eq!(system[0].mass, 2e-10 * biffle::SOLAR_MASS);
+        assert_eq!(system[0].position, [1., 2., 3.]);
+        assert_eq!(system[0].velocity, [0.5 * biffle::DAYS_PER_YEAR, 0., -biffle::DAYS_PER_YEAR]);
+    }
+}
@@ -0,0 +1,255 @@
+//! Checked runs, failing as soon as the state stops being finite.
+//!
+//! The kernels do not check what they compute: two bodies on the same spot
+//! turn their velocities into NaN, which spreads to every body within a step
+//! and only shows when the energy is printed at the end of the run. `Checked`
+//! validates the positions and velocities every few steps instead, and stops
+//! the run with a `Diverged` error naming the step, the body and the pair of
+//! bodies at fault, along with the last state that passed the check.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::checkpoint::Checkpoint;
+use crate::integrator::Integrator;
+use crate::NBodySimulator;
+
+/// A run stopped by a position or velocity that is not finite, or beyond the
+/// limit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diverged {
+    /// Step after which the check failed.
+    pub step: usize,
+    /// Lowest index of a body that failed the check.
+    pub body: usize,
+    /// Closest pair of bodies, in the last state that passed, of which at
+    /// least one failed: the likeliest cause, as a close encounter sends the
+    /// velocities of both bodies towards infinity. `None` without such a
+    /// state, or with a single body.
+    pub pair: Option<(usize, usize)>,
+    /// Last state that passed the check, `None` if the initial state failed.
+    pub last_finite: Option<Box<Checkpoint>>,
+}
+
+impl fmt::Display for Diverged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "body {} diverged at step {}", self.body, self.step)?;
+        if let Some((i, j)) = self.pair {
+            write!(f, ", likely from bodies {} and {}", i, j)?;
+        }
+        if let Some(last_finite) = &self.last_finite {
+            write!(f, "; last finite at step {}", last_finite.step)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diverged {}
+
+/// Validates the state of a simulation every few steps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checked {
+    every: usize,
+    limit: f64,
+}
+
+impl Default for Checked {
+    /// Checks after every step.
+    fn default() -> Checked {
+        Checked::every(1)
+    }
+}
+
+impl Checked {
+    /// Checks after every `every`th step, and after the last one.
+    pub fn every(every: usize) -> Checked {
+        assert!(every > 0, "need to check at least every step");
+        Checked { every, limit: f64::INFINITY }
+    }
+
+    /// Also fails on coordinates of a position or velocity larger than
+    /// `limit` in magnitude; by default only infinities and NaN fail.
+    pub fn with_limit(self, limit: f64) -> Checked {
+        Checked { limit, ..self }
+    }
+
+    pub fn limit(&self) -> f64 {
+        self.limit
+    }
+
+    /// Whether each body's position and velocity pass the check.
+    fn valid<S: NBodySimulator>(&self, sim: &S) -> Vec<bool> {
+        let valid = |vector: &[f64; 3]| vector.iter().all(|x| x.abs() <= self.limit);
+        sim.positions()
+            .iter()
+            .zip(&sim.velocities())
+            .map(|(position, velocity)| valid(position) && valid(velocity))
+            .collect()
+    }
+
+    /// Runs `sim` for `steps` steps of `dt` with `integrator`, checking the
+    /// initial state and every `every`th step.
+    ///
+    /// On failure `sim` is left in the state that failed.
+    pub fn advance<S: NBodySimulator>(
+        &self,
+        sim: &mut S,
+        integrator: Integrator,
+        steps: usize,
+        dt: f64,
+    ) -> Result<(), Diverged> {
+        let mut last_finite = None;
+        let mut step = 0;
+        loop {
+            let valid = self.valid(sim);
+            if let Some(body) = valid.iter().position(|&valid| !valid) {
+                let pair = last_finite.as_deref().and_then(|last| culprits(last, &valid));
+                return Err(Diverged { step, body, pair, last_finite });
+            }
+            if step == steps {
+                return Ok(());
+            }
+            last_finite = Some(Box::new(Checkpoint::of(sim, step as u64, dt)));
+
+            let chunk = self.every.min(steps - step);
+            integrator.advance(sim, chunk, dt);
+            step += chunk;
+        }
+    }
+}
+
+/// Closest pair in `last` of which at least one body is not `valid`.
+fn culprits(last: &Checkpoint, valid: &[bool]) -> Option<(usize, usize)> {
+    let mut closest = None;
+    let mut closest_squared = f64::INFINITY;
+    for (i, a) in last.bodies.iter().enumerate() {
+        for (j, b) in last.bodies.iter().enumerate().skip(i + 1) {
+            if valid[i] && valid[j] {
+                continue;
+            }
+            let d = [a.position[0] - b.position[0], a.position[1] - b.position[1], a.position[2] - b.position[2]];
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+            if closest.is_none() || distance_squared < closest_squared {
+                closest_squared = distance_squared;
+                closest = Some((i, j));
+            }
+        }
+    }
+    closest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, body, rehnberger, soa, thaumant, BodyState};
+
+    #[test]
+    fn stable_runs_pass_unchanged() {
+        let mut checked = biffle::Simulation::starting_state();
+        checked.offset_momentum();
+        let mut plain = biffle::Simulation::starting_state();
+        plain.offset_momentum();
+
+        Checked::every(7).advance(&mut checked, Integrator::SemiImplicitEuler, 1000, 0.01).unwrap();
+        plain.advance(1000, 0.01);
+        assert_eq!(checked.bodies(), plain.bodies());
+        Checked::default().advance(&mut checked, Integrator::Leapfrog, 0, 0.01).unwrap();
+    }
+
+    /// The Jovian planets and a moon on top of Saturn.
+    fn coincident_system() -> Vec<BodyState> {
+        let mut bodies = body::starting_state();
+        bodies.push(BodyState { name: "Titan".to_string(), mass: 1e-8, ..bodies[2].clone() });
+        bodies
+    }
+
+    fn coincident<S: NBodySimulator>() {
+        let bodies = coincident_system();
+        let mut sim = S::from_bodies(&bodies);
+        let error = Checked::default().advance(&mut sim, Integrator::SemiImplicitEuler, 100, 0.01).unwrap_err();
+        assert_eq!((error.step, error.body, error.pair), (1, 2, Some((2, 5))), "{}", S::NAME);
+        let last_finite = error.last_finite.unwrap();
+        assert_eq!(last_finite.step, 0);
+        assert_eq!(last_finite.implementation, S::NAME);
+        assert_eq!(last_finite.bodies, S::from_bodies(&bodies).bodies());
+    }
+
+    #[test]
+    fn coincident_bodies_fail_at_the_first_step() {
+        coincident::<biffle::Simulation>();
+        coincident::<rehnberger::Simulation>();
+        coincident::<soa::Simulation>();
+        coincident::<thaumant::Simulation>();
+    }
+
+    #[test]
+    fn checks_every_few_steps() {
+        let bodies = coincident_system();
+        let mut plain = biffle::Simulation::from_bodies(&bodies);
+        plain.advance(25, 0.01);
+        assert!(plain.velocities().iter().flatten().all(|v| v.is_nan()));
+
+        // By the check every body has failed, but the pair is still found.
+        let mut sim = biffle::Simulation::from_bodies(&bodies);
+        let error = Checked::every(10).advance(&mut sim, Integrator::SemiImplicitEuler, 25, 0.01).unwrap_err();
+        assert_eq!((error.step, error.body, error.pair), (10, 0, Some((2, 5))));
+        assert_eq!(error.last_finite.as_ref().unwrap().step, 0);
+        assert_eq!(error.to_string(), "body 0 diverged at step 10, likely from bodies 2 and 5; last finite at step 0");
+
+        // Short of the next check, the last step is checked.
+        let mut sim = biffle::Simulation::from_bodies(&bodies);
+        let error = Checked::every(100).advance(&mut sim, Integrator::SemiImplicitEuler, 25, 0.01).unwrap_err();
+        assert_eq!(error.step, 25);
+    }
+
+    #[test]
+    fn close_encounter_beyond_limit() {
+        // Two heavy bodies a thousandth apart, at rest beyond Neptune.
+        let mut bodies = body::starting_state();
+        for &y in [0., 1e-3].iter() {
+            bodies.push(BodyState { name: String::new(), position: [40., y, 0.], velocity: [0.; 3], mass: 1. });
+        }
+
+        // They fling each other out at 10000 AU a year, which is finite.
+        let mut sim = biffle::Simulation::from_bodies(&bodies);
+        Checked::default().advance(&mut sim, Integrator::SemiImplicitEuler, 10, 0.01).unwrap();
+        assert!(sim.velocities()[5][1] > 1e3, "{:?}", sim.velocities());
+
+        let mut sim = biffle::Simulation::from_bodies(&bodies);
+        let limited = Checked::default().with_limit(1e3);
+        assert_eq!(limited.limit(), 1e3);
+        let error = limited.advance(&mut sim, Integrator::SemiImplicitEuler, 10, 0.01).unwrap_err();
+        assert_eq!((error.step, error.body, error.pair), (1, 5, Some((5, 6))), "{}", error);
+
+        // The last finite state resumes into the failure.
+        let mut resumed: biffle::Simulation = error.last_finite.unwrap().restore().unwrap();
+        resumed.advance(1, 0.01);
+        assert_eq!(resumed.bodies(), sim.bodies());
+
+        // So it does with settings other than the defaults of `from_bodies`.
+        let mut sim = rehnberger::Simulation::with_kernel(
+            bodies.iter().map(rehnberger::Body::from).collect(),
+            rehnberger::Kernel::Scalar,
+        )
+        .with_precision(rehnberger::Precision::Exact)
+        .with_softening(1e-4);
+        let error = limited.advance(&mut sim, Integrator::SemiImplicitEuler, 10, 0.01).unwrap_err();
+        assert_eq!((error.step, error.body, error.pair), (1, 5, Some((5, 6))), "{}", error);
+        let last_finite = error.last_finite.unwrap();
+        assert_eq!(last_finite.settings, sim.settings());
+        let mut resumed: rehnberger::Simulation = last_finite.restore().unwrap();
+        resumed.advance(1, 0.01);
+        assert_eq!(resumed.bodies(), sim.bodies());
+        assert_eq!(resumed.settings(), sim.settings());
+    }
+
+    #[test]
+    fn bad_initial_state() {
+        let mut bodies = body::starting_state();
+        bodies[3].velocity[1] = f64::NAN;
+        let mut sim = thaumant::Simulation::from_bodies(&bodies);
+        let error = Checked::default().advance(&mut sim, Integrator::Leapfrog, 10, 0.01).unwrap_err();
+        assert_eq!(error, Diverged { step: 0, body: 3, pair: None, last_finite: None });
+        assert_eq!(error.to_string(), "body 3 diverged at step 0");
+    }
+}
@@ -0,0 +1,463 @@
+//! Binary checkpoints, to pause a long run and resume it later.
+//!
+//! A checkpoint holds the bodies of a simulation together with the number of
+//! steps taken, the time-step and the implementation that produced it, with
+//! its settings. All numbers are little-endian:
+//!
+//! | bytes     | content                                                 |
+//! |-----------|---------------------------------------------------------|
+//! | 8         | magic, `NBODYCKP`                                       |
+//! | 4         | format version, `u32`                                   |
+//! | 1 + n     | implementation id length, `u8`, then the UTF-8 id       |
+//! | 8         | steps taken, `u64`                                      |
+//! | 8         | time-step, `f64`                                        |
+//! | 8         | number of bodies, `u64`                                 |
+//! | 56 each   | position, velocity and mass of each body, 7 `f64`       |
+//! | 8         | FNV-1a hash of all the bytes above, `u64`               |
+//!
+//! The implementation id is the implementation's name, followed by a space
+//! and its `NBodySimulator::settings` if it has any, such as
+//! `rehnberger kernel=scalar precision=exact softening=1e-3`; the checksum
+//! covers both.
+//!
+//! Every `f64` is stored bit for bit, every implementation converts its bodies
+//! to and from `BodyState` exactly, and its settings are written so they read
+//! back exactly, so a restored simulation continues exactly as the original
+//! would have. Body names are not stored.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use crate::{BodyState, NBodySimulator};
+
+const MAGIC: &[u8; 8] = b"NBODYCKP";
+
+/// Version written by `Checkpoint::write`, the only one `read` accepts.
+pub const VERSION: u32 = 1;
+
+/// State of a simulation at some step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// `NBodySimulator::NAME` of the implementation that was running.
+    pub implementation: String,
+    /// `NBodySimulator::settings` of the simulation.
+    pub settings: String,
+    /// Steps taken before the checkpoint.
+    pub step: u64,
+    pub dt: f64,
+    pub bodies: Vec<BodyState>,
+}
+
+/// Why a checkpoint could not be read or restored.
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    /// The data does not start with the checkpoint magic.
+    NotACheckpoint,
+    UnsupportedVersion(u32),
+    /// The data ends before the checkpoint does.
+    Truncated,
+    /// The data does not match its checksum.
+    Corrupted,
+    /// The implementation id is not valid UTF-8 or is too long.
+    InvalidImplementation,
+    /// The implementation does not accept the settings.
+    InvalidSettings(String),
+    /// The checkpoint was taken with a different implementation.
+    WrongImplementation { expected: String, found: String },
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(error) => write!(f, "{}", error),
+            CheckpointError::NotACheckpoint => write!(f, "not a checkpoint"),
+            CheckpointError::UnsupportedVersion(version) => {
+                write!(f, "unsupported checkpoint version {}", version)
+            }
+            CheckpointError::Truncated => write!(f, "checkpoint is truncated"),
+            CheckpointError::Corrupted => write!(f, "checkpoint does not match its checksum"),
+            CheckpointError::InvalidImplementation => write!(f, "invalid implementation id"),
+            CheckpointError::InvalidSettings(error) => write!(f, "invalid settings: {}", error),
+            CheckpointError::WrongImplementation { expected, found } => {
+                write!(f, "checkpoint of {} cannot be restored into {}", found, expected)
+            }
+        }
+    }
+}
+
+impl Error for CheckpointError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CheckpointError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(error: io::Error) -> CheckpointError {
+        CheckpointError::Io(error)
+    }
+}
+
+/// Splits settings as written by `NBodySimulator::settings` into `key=value`
+/// pairs.
+pub(crate) fn parse_settings(settings: &str) -> Result<Vec<(&str, &str)>, String> {
+    settings
+        .split_whitespace()
+        .map(|setting| setting.split_once('=').ok_or_else(|| format!("malformed setting {:?}", setting)))
+        .collect()
+}
+
+/// Parses the value of setting `key`.
+pub(crate) fn parse_setting<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {} {:?}", key, value))
+}
+
+pub(crate) fn unknown_setting(key: &str) -> String {
+    format!("unknown setting {:?}", key)
+}
+
+/// Settings of an implementation whose only setting is a softening length,
+/// left out at its default of 0.
+///
+/// The length is written in exponent notation, which reads back to the same
+/// bits.
+pub(crate) fn softening_settings(softening: f64) -> String {
+    if softening == 0. {
+        String::new()
+    } else {
+        format!("softening={:e}", softening)
+    }
+}
+
+/// Softening length in settings written by `softening_settings`.
+pub(crate) fn parse_softening(settings: &str) -> Result<f64, String> {
+    let mut softening = 0.;
+    for (key, value) in parse_settings(settings)? {
+        match key {
+            "softening" => softening = parse_setting(key, value)?,
+            _ => return Err(unknown_setting(key)),
+        }
+    }
+    Ok(softening)
+}
+
+/// 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Reads fixed-size fields from the front of a byte slice.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CheckpointError> {
+        if self.0.len() < len {
+            return Err(CheckpointError::Truncated);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, CheckpointError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn vector(&mut self) -> Result<[f64; 3], CheckpointError> {
+        Ok([self.f64()?, self.f64()?, self.f64()?])
+    }
+}
+
+impl Checkpoint {
+    /// Checkpoint of `sim` after `step` steps of `dt`.
+    pub fn of<S: NBodySimulator>(sim: &S, step: u64, dt: f64) -> Checkpoint {
+        Checkpoint {
+            implementation: S::NAME.to_string(),
+            settings: sim.settings(),
+            step,
+            dt,
+            bodies: sim.bodies(),
+        }
+    }
+
+    /// Recreates the simulation the checkpoint was taken of, with its
+    /// settings.
+    pub fn restore<S: NBodySimulator>(&self) -> Result<S, CheckpointError> {
+        if self.implementation != S::NAME {
+            return Err(CheckpointError::WrongImplementation {
+                expected: S::NAME.to_string(),
+                found: self.implementation.clone(),
+            });
+        }
+        let mut sim = S::from_bodies(&self.bodies);
+        sim.apply_settings(&self.settings).map_err(CheckpointError::InvalidSettings)?;
+        Ok(sim)
+    }
+
+    /// The implementation name, followed by the settings if there are any.
+    fn id(&self) -> String {
+        if self.settings.is_empty() {
+            self.implementation.clone()
+        } else {
+            format!("{} {}", self.implementation, self.settings)
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), CheckpointError> {
+        let id = self.id();
+        if id.len() > usize::from(u8::MAX) || self.implementation.contains(' ') {
+            return Err(CheckpointError::InvalidImplementation);
+        }
+
+        let mut bytes = Vec::with_capacity(45 + id.len() + 56 * self.bodies.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(id.len() as u8);
+        bytes.extend_from_slice(id.as_bytes());
+        bytes.extend_from_slice(&self.step.to_le_bytes());
+        bytes.extend_from_slice(&self.dt.to_le_bytes());
+        bytes.extend_from_slice(&(self.bodies.len() as u64).to_le_bytes());
+        for body in &self.bodies {
+            for value in body.position.iter().chain(&body.velocity).chain(Some(&body.mass)) {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        let checksum = fnv1a(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Reads a checkpoint, which must be all that is left in `reader`.
+    pub fn read<R: Read>(mut reader: R) -> Result<Checkpoint, CheckpointError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        let mut fields = Fields(&bytes);
+        if fields.take(MAGIC.len()).map_err(|_| CheckpointError::NotACheckpoint)? != MAGIC {
+            return Err(CheckpointError::NotACheckpoint);
+        }
+        let version = u32::from_le_bytes(fields.array()?);
+        if version != VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        if fields.0.len() < 8 {
+            return Err(CheckpointError::Truncated);
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 8);
+        if Fields(checksum).u64()? != fnv1a(content) {
+            // A cut-off checkpoint fails the checksum too; tell the two apart
+            // where the header says how long it should be.
+            return Err(match Checkpoint::parse(content) {
+                Err(CheckpointError::Truncated) => CheckpointError::Truncated,
+                _ => CheckpointError::Corrupted,
+            });
+        }
+        Checkpoint::parse(content)
+    }
+
+    /// Parses everything but the checksum.
+    fn parse(content: &[u8]) -> Result<Checkpoint, CheckpointError> {
+        let mut fields = Fields(&content[MAGIC.len() + 4..]);
+        let id_len = fields.array::<1>()?[0];
+        let id = std::str::from_utf8(fields.take(usize::from(id_len))?)
+            .map_err(|_| CheckpointError::InvalidImplementation)?;
+        let (implementation, settings) = id.split_once(' ').unwrap_or((id, ""));
+        let (implementation, settings) = (implementation.to_string(), settings.to_string());
+        let step = fields.u64()?;
+        let dt = fields.f64()?;
+        let count = fields.u64()?;
+        if count > (fields.0.len() / 56) as u64 {
+            return Err(CheckpointError::Truncated);
+        }
+
+        let mut bodies = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            bodies.push(BodyState {
+                name: String::new(),
+                position: fields.vector()?,
+                velocity: fields.vector()?,
+                mass: fields.f64()?,
+            });
+        }
+        if !fields.0.is_empty() {
+            return Err(CheckpointError::Corrupted);
+        }
+        Ok(Checkpoint { implementation, settings, step, dt, bodies })
+    }
+}
+
+/// Writes a checkpoint of `sim` after `step` steps of `dt` to `writer`.
+pub fn save<S: NBodySimulator, W: Write>(sim: &S, step: u64, dt: f64, writer: W) -> Result<(), CheckpointError> {
+    Checkpoint::of(sim, step, dt).write(writer)
+}
+
+/// Reads a checkpoint of an `S` simulation, returning it with the number of
+/// steps taken and the time-step.
+pub fn load<S: NBodySimulator, R: Read>(reader: R) -> Result<(S, u64, f64), CheckpointError> {
+    let checkpoint = Checkpoint::read(reader)?;
+    Ok((checkpoint.restore()?, checkpoint.step, checkpoint.dt))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{barnes_hut, biffle, body, rehnberger, soa, thaumant};
+
+    fn checkpoint_bytes() -> Vec<u8> {
+        let sim = biffle::Simulation::from_bodies(&body::asteroid_belt(3));
+        let mut bytes = Vec::new();
+        save(&sim, 7, 0.01, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn resumes_exactly<S: NBodySimulator>() {
+        let bodies = body::asteroid_belt(10);
+        let mut straight = S::from_bodies(&bodies);
+        straight.offset_momentum();
+        let mut paused = S::from_bodies(&bodies);
+        paused.offset_momentum();
+
+        straight.advance(1000, 0.01);
+        paused.advance(500, 0.01);
+        let mut bytes = Vec::new();
+        save(&paused, 500, 0.01, &mut bytes).unwrap();
+        drop(paused);
+
+        let (mut resumed, step, dt) = load::<S, _>(&bytes[..]).unwrap();
+        assert_eq!((step, dt), (500, 0.01));
+        resumed.advance(1000 - step as usize, dt);
+        assert_eq!(resumed.bodies(), straight.bodies(), "{}", S::NAME);
+    }
+
+    #[test]
+    fn resume() {
+        resumes_exactly::<biffle::Simulation>();
+        resumes_exactly::<rehnberger::Simulation>();
+        resumes_exactly::<soa::Simulation>();
+        resumes_exactly::<thaumant::Simulation>();
+    }
+
+    #[test]
+    fn resume_with_settings() {
+        let bodies = body::asteroid_belt(10);
+        let new = || {
+            rehnberger::Simulation::with_kernel(bodies.iter().map(rehnberger::Body::from).collect(), rehnberger::Kernel::Scalar)
+                .with_precision(rehnberger::Precision::Exact)
+                .with_softening(0.1)
+        };
+        let mut straight = new();
+        straight.advance(1000, 0.01);
+        let mut paused = new();
+        paused.advance(500, 0.01);
+
+        let mut bytes = Vec::new();
+        save(&paused, 500, 0.01, &mut bytes).unwrap();
+        let checkpoint = Checkpoint::read(&bytes[..]).unwrap();
+        assert_eq!(checkpoint.implementation, "rehnberger");
+        assert_eq!(checkpoint.settings, "kernel=scalar precision=exact softening=1e-1");
+        assert_eq!(checkpoint.settings, paused.settings());
+
+        let mut resumed: rehnberger::Simulation = checkpoint.restore().unwrap();
+        assert_eq!(resumed.settings(), paused.settings());
+        resumed.advance(500, 0.01);
+        assert_eq!(resumed.bodies(), straight.bodies());
+
+        let softened = thaumant::Simulation::from_bodies(&bodies).with_softening(1e-3);
+        let restored: thaumant::Simulation = Checkpoint::of(&softened, 0, 0.01).restore().unwrap();
+        assert_eq!(restored.softening(), 1e-3);
+        let softened = biffle::Simulation::from_bodies(&bodies).with_softening(1e-3);
+        let restored: biffle::Simulation = Checkpoint::of(&softened, 0, 0.01).restore().unwrap();
+        assert_eq!(restored.softening(), 1e-3);
+
+        let tree = barnes_hut::Simulation::from_bodies(&bodies).with_theta(0.7).with_softening(1e-3);
+        let checkpoint = Checkpoint::of(&tree, 0, 0.01);
+        assert_eq!(checkpoint.settings, "theta=7e-1 softening=1e-3");
+        let restored: barnes_hut::Simulation = checkpoint.restore().unwrap();
+        assert_eq!((restored.theta(), restored.softening()), (0.7, 1e-3));
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        let mut checkpoint = Checkpoint::read(&checkpoint_bytes()[..]).unwrap();
+        checkpoint.settings = "theta=5e-1".to_string();
+        assert!(matches!(checkpoint.restore::<biffle::Simulation>(), Err(CheckpointError::InvalidSettings(_))));
+
+        checkpoint.implementation = "rehnberger".to_string();
+        for settings in ["kernel=avx512", "precision=approximate:x", "softening", "fill=1"].iter() {
+            checkpoint.settings = settings.to_string();
+            match checkpoint.restore::<rehnberger::Simulation>() {
+                Err(CheckpointError::InvalidSettings(_)) => {}
+                other => panic!("{}: {:?}", settings, other.map(|sim| sim.settings())),
+            }
+        }
+
+        // The checksum covers the settings.
+        let sim = thaumant::Simulation::from_bodies(&body::asteroid_belt(3)).with_softening(0.5);
+        let mut bytes = Vec::new();
+        save(&sim, 0, 0.01, &mut bytes).unwrap();
+        assert_eq!(&bytes[12..36], b"\x17thaumant softening=5e-1");
+        bytes[32] = b'4';
+        assert!(matches!(Checkpoint::read(&bytes[..]), Err(CheckpointError::Corrupted)));
+    }
+
+    #[test]
+    fn layout() {
+        let bytes = checkpoint_bytes();
+        assert_eq!(bytes.len(), 8 + 4 + 1 + 6 + 8 + 8 + 8 + 3 * 56 + 8);
+        assert_eq!(&bytes[..8], b"NBODYCKP");
+        assert_eq!(&bytes[8..12], &[1, 0, 0, 0]);
+        assert_eq!(&bytes[12..19], b"\x06biffle");
+        assert_eq!(&bytes[19..27], &[7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[27..35], &0.01f64.to_le_bytes());
+        assert_eq!(&bytes[35..43], &[3, 0, 0, 0, 0, 0, 0, 0]);
+        // The Sun's mass ends the first body.
+        assert_eq!(&bytes[91..99], &biffle::SOLAR_MASS.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_bad_data() {
+        let bytes = checkpoint_bytes();
+
+        let mut corrupted = bytes.clone();
+        corrupted[100] ^= 1;
+        assert!(matches!(Checkpoint::read(&corrupted[..]), Err(CheckpointError::Corrupted)));
+
+        assert!(matches!(Checkpoint::read(&bytes[..bytes.len() - 9]), Err(CheckpointError::Truncated)));
+        assert!(matches!(Checkpoint::read(&bytes[..16]), Err(CheckpointError::Truncated)));
+        assert!(matches!(Checkpoint::read(&bytes[..4]), Err(CheckpointError::NotACheckpoint)));
+        assert!(matches!(Checkpoint::read(&b"not a checkpoint"[..]), Err(CheckpointError::NotACheckpoint)));
+
+        let mut future = bytes.clone();
+        future[8] = 2;
+        assert!(matches!(Checkpoint::read(&future[..]), Err(CheckpointError::UnsupportedVersion(2))));
+
+        match load::<thaumant::Simulation, _>(&bytes[..]) {
+            Err(CheckpointError::WrongImplementation { expected, found }) => {
+                assert_eq!((expected.as_str(), found.as_str()), ("thaumant", "biffle"));
+            }
+            other => panic!("{:?}", other.map(|(_, step, dt)| (step, dt))),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Detection and merging of colliding bodies.
+//!
+//! Without softening, the kernels divide by the cube of the distance between
+//! two bodies, so a close encounter sends their velocities towards infinity
+//! and a coincidence fills the whole state with NaN. `Collisions` checks the
+//! bodies after every step instead, and merges any two closer than a given
+//! radius into one body of their combined mass, at their centre of mass and
+//! moving with their combined momentum.
+//!
+//! Indices in the reported `Merger`s refer to the bodies as they were just
+//! before that merger: the lower index takes the merged body and the higher
+//! one is removed, shifting the bodies after it down by one.
+
+use crate::integrator::Integrator;
+use crate::{BodyState, NBodySimulator};
+
+/// Merger of two bodies into one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merger {
+    /// Step after which the bodies were found too close.
+    pub step: usize,
+    /// Index of the body that takes the merged one.
+    pub survivor: usize,
+    /// Index of the body removed by the merger, greater than `survivor`.
+    pub absorbed: usize,
+    /// Distance between the two bodies when they were merged.
+    pub distance: f64,
+    /// The body both were merged into, named as the heavier of the two.
+    pub merged: BodyState,
+}
+
+/// Merges bodies closer to each other than a collision radius.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Collisions {
+    radius: f64,
+}
+
+impl Collisions {
+    /// Merges bodies closer than `radius`.
+    pub fn new(radius: f64) -> Collisions {
+        assert!(radius >= 0., "collision radius must not be negative");
+        Collisions { radius }
+    }
+
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    /// The closest pair of bodies closer than the radius, with the distance
+    /// between them.
+    fn closest_pair(&self, positions: &[[f64; 3]]) -> Option<(usize, usize, f64)> {
+        let mut closest = None;
+        let mut closest_squared = self.radius * self.radius;
+        for (i, a) in positions.iter().enumerate() {
+            for (j, b) in positions.iter().enumerate().skip(i + 1) {
+                let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+                let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+                if distance_squared < closest_squared {
+                    closest_squared = distance_squared;
+                    closest = Some((i, j));
+                }
+            }
+        }
+        closest.map(|(i, j)| (i, j, closest_squared.sqrt()))
+    }
+
+    /// Merges the closest pair of bodies closer than the radius, then the
+    /// closest of the rest, until no two bodies are closer.
+    ///
+    /// The mergers are returned in order, each marked as happening at `step`.
+    pub fn merge(&self, bodies: &mut Vec<BodyState>, step: usize) -> Vec<Merger> {
+        let mut mergers = Vec::new();
+        loop {
+            let positions: Vec<[f64; 3]> = bodies.iter().map(|body| body.position).collect();
+            let (survivor, absorbed, distance) = match self.closest_pair(&positions) {
+                Some(pair) => pair,
+                None => return mergers,
+            };
+            let merged = merge_pair(&bodies[survivor], &bodies[absorbed]);
+            bodies[survivor] = merged.clone();
+            bodies.remove(absorbed);
+            mergers.push(Merger { step, survivor, absorbed, distance, merged });
+        }
+    }
+
+    /// Runs `sim` for `steps` steps of `dt` with `integrator`, merging
+    /// colliding bodies after every step, and returns the mergers in order.
+    ///
+    /// Bodies already closer than the radius are merged before the first
+    /// step, as happening at step 0.
+    pub fn advance<S: NBodySimulator>(
+        &self,
+        sim: &mut S,
+        integrator: Integrator,
+        steps: usize,
+        dt: f64,
+    ) -> Vec<Merger> {
+        let mut mergers = self.merge_simulation(sim, 0);
+        for step in 1..=steps {
+            integrator.step(sim, dt);
+            mergers.extend(self.merge_simulation(sim, step));
+        }
+        mergers
+    }
+
+    fn merge_simulation<S: NBodySimulator>(&self, sim: &mut S, step: usize) -> Vec<Merger> {
+        if self.closest_pair(&sim.positions()).is_none() {
+            return Vec::new();
+        }
+        let mut bodies = sim.bodies();
+        let mergers = self.merge(&mut bodies, step);
+        sim.set_bodies(&bodies);
+        mergers
+    }
+}
+
+/// One body of the combined mass of `a` and `b`, at their centre of mass and
+/// with their combined momentum.
+///
+/// Massless bodies merge at their midpoint, with their mean velocity.
+fn merge_pair(a: &BodyState, b: &BodyState) -> BodyState {
+    let mass = a.mass + b.mass;
+    let (wa, wb) = if mass == 0. { (0.5, 0.5) } else { (a.mass / mass, b.mass / mass) };
+    let mut merged = BodyState {
+        name: if b.mass > a.mass { b.name.clone() } else { a.name.clone() },
+        position: [0.; 3],
+        velocity: [0.; 3],
+        mass,
+    };
+    for m in 0..3 {
+        merged.position[m] = wa * a.position[m] + wb * b.position[m];
+        merged.velocity[m] = wa * a.velocity[m] + wb * b.velocity[m];
+    }
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diagnostics::momentum;
+    use crate::{barnes_hut, biffle, body, parallel, rehnberger, soa, thaumant};
+
+    fn body(position: [f64; 3], velocity: [f64; 3], mass: f64) -> BodyState {
+        BodyState { name: String::new(), position, velocity, mass }
+    }
+
+    /// Two bodies falling towards each other head-on, and a third far away.
+    fn head_on() -> Vec<BodyState> {
+        vec![
+            body([-1., 0., 0.], [0.5, 0., 0.], 2.),
+            body([100., 0., 0.], [0., 1., 0.], 1e-3),
+            body([1., 0., 0.], [-1., 0., 0.], 1.),
+        ]
+    }
+
+    #[test]
+    fn merges_conserve_mass_and_momentum() {
+        let mut bodies = vec![
+            BodyState { name: "A".to_string(), ..body([0., 0., 0.], [1., 0., 0.], 1.) },
+            BodyState { name: "B".to_string(), ..body([0.5, 0., 0.], [0., 3., 0.], 3.) },
+            BodyState { name: "C".to_string(), ..body([5., 0., 0.], [0., 0., 1.], 1.) },
+        ];
+        let before = momentum(&bodies);
+        let mergers = Collisions::new(1.).merge(&mut bodies, 7);
+
+        assert_eq!(mergers.len(), 1);
+        let merger = &mergers[0];
+        assert_eq!((merger.step, merger.survivor, merger.absorbed), (7, 0, 1));
+        assert_eq!(merger.distance, 0.5);
+        assert_eq!(merger.merged.name, "B");
+        assert_eq!(merger.merged.mass, 4.);
+        assert_eq!(merger.merged.position, [0.375, 0., 0.]);
+        assert_eq!(merger.merged.velocity, [0.25, 2.25, 0.]);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], merger.merged);
+        assert_eq!(bodies[1].name, "C");
+        assert_eq!(momentum(&bodies), before);
+    }
+
+    #[test]
+    fn merges_chain_closest_first() {
+        let mut bodies = vec![
+            body([0., 0., 0.], [0.; 3], 1.),
+            body([0.3, 0., 0.], [0.; 3], 1.),
+            body([0.45, 0., 0.], [0.; 3], 2.),
+            body([0., 0., 0.], [0.; 3], 0.),
+        ];
+        let mergers = Collisions::new(0.5).merge(&mut bodies, 0);
+        let pairs: Vec<_> = mergers.iter().map(|merger| (merger.survivor, merger.absorbed)).collect();
+
+        // The coincident bodies first, then the pair 0.15 apart, then the
+        // two left 0.4 apart.
+        assert_eq!(pairs, vec![(0, 3), (1, 2), (0, 1)]);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].mass, 4.);
+        assert!((bodies[0].position[0] - 0.3).abs() < 1e-15, "{:?}", bodies[0]);
+
+        let mut massless = vec![body([0.; 3], [1., 0., 0.], 0.), body([0.; 3], [0., 1., 0.], 0.)];
+        Collisions::new(0.).merge(&mut massless, 0);
+        assert_eq!(massless.len(), 2);
+        Collisions::new(1e-9).merge(&mut massless, 0);
+        assert_eq!(massless, vec![body([0.; 3], [0.5, 0.5, 0.], 0.)]);
+    }
+
+    fn head_on_collision<S: NBodySimulator>(sim: S) -> S {
+        let mut sim = sim;
+        let before = momentum(&sim.bodies());
+        let mergers = Collisions::new(0.05).advance(&mut sim, Integrator::Leapfrog, 1000, 0.001);
+
+        assert_eq!(mergers.len(), 1, "{:?}", mergers);
+        assert_eq!((mergers[0].survivor, mergers[0].absorbed), (0, 2));
+        assert!(mergers[0].step > 0 && mergers[0].step < 1000);
+        assert_eq!(mergers[0].merged.mass, 3.);
+
+        let bodies = sim.bodies();
+        assert_eq!(bodies.len(), 2);
+        for (a, b) in momentum(&bodies).iter().zip(&before) {
+            assert!((a - b).abs() < 1e-12, "{:?} != {:?}", momentum(&bodies), before);
+        }
+        assert!(sim.energy().is_finite());
+        sim
+    }
+
+    #[test]
+    fn collisions_during_simulation() {
+        head_on_collision(biffle::Simulation::from_bodies(&head_on()));
+        let sim = head_on_collision(thaumant::Simulation::from_bodies(&head_on()).with_softening(1e-3));
+        assert_eq!(sim.softening(), 1e-3);
+        head_on_collision(rehnberger::Simulation::from_bodies(&head_on()).with_softening(1e-3));
+        head_on_collision(soa::Simulation::from_bodies(&head_on()).with_softening(1e-3));
+        let sim = head_on_collision(parallel::Simulation::from_bodies(&head_on()).with_softening(1e-3));
+        assert_eq!(sim.softening(), 1e-3);
+        // Cells acting as one mass break the symmetry of the forces, and with
+        // it the conservation of momentum, unless every cell is opened.
+        head_on_collision(barnes_hut::Simulation::from_bodies(&head_on()).with_theta(0.).with_softening(1e-3));
+
+        // Without merging, the pair passes through each other and flies
+        // apart far faster than it came, if the state is finite at all.
+        let mut sim = biffle::Simulation::from_bodies(&head_on());
+        Integrator::Leapfrog.advance(&mut sim, 1000, 0.001);
+        let speed = sim.velocities()[0][0].abs();
+        assert!(speed.is_nan() || speed > 10., "{:?}", sim.velocities());
+    }
+
+    #[test]
+    fn jovian_planets_do_not_collide() {
+        let mut merging = biffle::Simulation::starting_state();
+        merging.offset_momentum();
+        let mut plain = biffle::Simulation::starting_state();
+        plain.offset_momentum();
+
+        let mergers = Collisions::new(0.01).advance(&mut merging, Integrator::SemiImplicitEuler, 1000, 0.01);
+        plain.advance(1000, 0.01);
+        assert!(mergers.is_empty());
+        assert_eq!(merging.bodies(), plain.bodies());
+        assert_eq!(merging.bodies().len(), body::starting_state().len());
+    }
+}
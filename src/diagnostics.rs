@@ -0,0 +1,354 @@
+//! Checks of the physical properties a simulation should preserve.
+
+use crate::integrator::Integrator;
+use crate::{BodyState, NBodySimulator};
+
+/// Largest difference in any component between two states of the same system.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Deviation {
+    pub position: f64,
+    pub velocity: f64,
+}
+
+impl Deviation {
+    /// Compares `a` and `b` body by body.
+    pub fn between(a: &[BodyState], b: &[BodyState]) -> Deviation {
+        assert_eq!(a.len(), b.len(), "states of different systems");
+        let mut deviation = Deviation::default();
+        for (a, b) in a.iter().zip(b) {
+            for m in 0..3 {
+                deviation.position = deviation.position.max((a.position[m] - b.position[m]).abs());
+                deviation.velocity = deviation.velocity.max((a.velocity[m] - b.velocity[m]).abs());
+            }
+        }
+        deviation
+    }
+}
+
+/// Steps `sim` forward by `steps` steps of `dt` with `integrator`, then back
+/// by as many steps of `-dt`, and returns how far it ends from where it
+/// started.
+///
+/// The symmetric schemes (`Leapfrog`, `Yoshida4`, `ForestRuth`) retrace their
+/// path exactly in exact arithmetic, so the deviation measures rounding alone.
+/// `SemiImplicitEuler` is symplectic but not symmetric: stepping back with
+/// `-dt` is not the inverse of stepping forward, and it leaves a deviation of
+/// order `dt`.
+pub fn reversibility<S: NBodySimulator>(sim: &mut S, integrator: Integrator, steps: usize, dt: f64) -> Deviation {
+    let start = sim.bodies();
+    integrator.advance(sim, steps, dt);
+    integrator.advance(sim, steps, -dt);
+    Deviation::between(&start, &sim.bodies())
+}
+
+/// Energy of a system split into its kinetic and potential parts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnergyBreakdown {
+    /// Total kinetic energy.
+    pub kinetic: f64,
+    /// Total potential energy; negative for a bound system.
+    pub potential: f64,
+    /// Both parts, added up term by term in the order of the implementation's
+    /// loop, so it matches its `compute_energy` to the last bit.
+    pub total: f64,
+    /// Kinetic energy of each body, in order.
+    pub per_body_kinetic: Vec<f64>,
+}
+
+impl EnergyBreakdown {
+    /// `2K / |U|`, which averages to 1 over time for a bound system in
+    /// equilibrium.
+    pub fn virial_ratio(&self) -> f64 {
+        2. * self.kinetic / self.potential.abs()
+    }
+}
+
+/// Sums the terms of a system's energy, in the order an implementation's loop
+/// computes them.
+///
+/// The loops are written once against this trait: a plain `f64` keeps just
+/// the total, without allocating, and an `EnergyBreakdown` keeps the parts.
+pub trait EnergyTerms {
+    /// Adds the kinetic energy of the next body.
+    fn add_kinetic(&mut self, kinetic: f64);
+
+    /// Adds the potential energy of a pair of bodies.
+    fn add_potential(&mut self, potential: f64);
+}
+
+impl EnergyTerms for f64 {
+    fn add_kinetic(&mut self, kinetic: f64) {
+        *self += kinetic;
+    }
+
+    fn add_potential(&mut self, potential: f64) {
+        *self += potential;
+    }
+}
+
+impl EnergyTerms for EnergyBreakdown {
+    fn add_kinetic(&mut self, kinetic: f64) {
+        self.kinetic += kinetic;
+        self.total += kinetic;
+        self.per_body_kinetic.push(kinetic);
+    }
+
+    fn add_potential(&mut self, potential: f64) {
+        self.potential += potential;
+        self.total += potential;
+    }
+}
+
+/// Position and velocity of the centre of mass.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CentreOfMass {
+    pub position: [f64; 3],
+    pub velocity: [f64; 3],
+}
+
+/// Quantities that gravitation alone conserves.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Invariants {
+    pub momentum: [f64; 3],
+    pub angular_momentum: [f64; 3],
+    pub centre_of_mass: CentreOfMass,
+}
+
+impl Invariants {
+    pub fn of(bodies: &[BodyState]) -> Invariants {
+        Invariants {
+            momentum: momentum(bodies),
+            angular_momentum: angular_momentum(bodies),
+            centre_of_mass: centre_of_mass(bodies),
+        }
+    }
+}
+
+/// Total linear momentum, `sum(m v)`.
+pub fn momentum(bodies: &[BodyState]) -> [f64; 3] {
+    let mut momentum = [0.; 3];
+    for body in bodies {
+        for (momentum, velocity) in momentum.iter_mut().zip(&body.velocity) {
+            *momentum += body.mass * velocity;
+        }
+    }
+    momentum
+}
+
+/// Total angular momentum about the origin, `sum(m r × v)`.
+pub fn angular_momentum(bodies: &[BodyState]) -> [f64; 3] {
+    let mut angular_momentum = [0.; 3];
+    for body in bodies {
+        let [x, y, z] = body.position;
+        let [vx, vy, vz] = body.velocity;
+        angular_momentum[0] += body.mass * (y * vz - z * vy);
+        angular_momentum[1] += body.mass * (z * vx - x * vz);
+        angular_momentum[2] += body.mass * (x * vy - y * vx);
+    }
+    angular_momentum
+}
+
+/// Mass-weighted mean position and velocity; zero for an empty system.
+pub fn centre_of_mass(bodies: &[BodyState]) -> CentreOfMass {
+    let total_mass: f64 = bodies.iter().map(|body| body.mass).sum();
+    let mut centre = CentreOfMass::default();
+    if total_mass == 0. {
+        return centre;
+    }
+    for body in bodies {
+        for m in 0..3 {
+            centre.position[m] += body.mass * body.position[m] / total_mass;
+            centre.velocity[m] += body.mass * body.velocity[m] / total_mass;
+        }
+    }
+    centre
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Change of the conserved quantities since the start of a run.
+///
+/// Energy drift is relative to the initial energy; the others are the length
+/// of the difference vector, as the initial momentum is usually zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Drift {
+    pub energy: f64,
+    pub momentum: f64,
+    pub angular_momentum: f64,
+    pub centre_of_mass_velocity: f64,
+}
+
+/// Drift of the conserved quantities after each step of a run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DriftReport {
+    pub initial_energy: f64,
+    pub initial: Invariants,
+    /// Drift after each step, in order.
+    pub steps: Vec<Drift>,
+}
+
+impl DriftReport {
+    /// Largest drift of each quantity over the run.
+    pub fn max(&self) -> Drift {
+        self.steps.iter().fold(Drift::default(), |max, drift| Drift {
+            energy: max.energy.max(drift.energy),
+            momentum: max.momentum.max(drift.momentum),
+            angular_momentum: max.angular_momentum.max(drift.angular_momentum),
+            centre_of_mass_velocity: max.centre_of_mass_velocity.max(drift.centre_of_mass_velocity),
+        })
+    }
+}
+
+/// Steps `sim` forward by `steps` steps of `dt` with `integrator`, recording
+/// how far the conserved quantities move from their initial values.
+pub fn drift_report<S: NBodySimulator>(sim: &mut S, integrator: Integrator, steps: usize, dt: f64) -> DriftReport {
+    let initial_energy = sim.energy();
+    let initial = Invariants::of(&sim.bodies());
+    let steps = (0..steps)
+        .map(|_| {
+            integrator.step(sim, dt);
+            let current = Invariants::of(&sim.bodies());
+            Drift {
+                energy: ((sim.energy() - initial_energy) / initial_energy).abs(),
+                momentum: distance(current.momentum, initial.momentum),
+                angular_momentum: distance(current.angular_momentum, initial.angular_momentum),
+                centre_of_mass_velocity: distance(
+                    current.centre_of_mass.velocity,
+                    initial.centre_of_mass.velocity,
+                ),
+            }
+        })
+        .collect();
+    DriftReport { initial_energy, initial, steps }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, body, rehnberger, soa, thaumant};
+
+    fn starting_reversibility<S: NBodySimulator>(integrator: Integrator, steps: usize) -> Deviation {
+        let mut sim = S::starting_state();
+        sim.offset_momentum();
+        reversibility(&mut sim, integrator, steps, 0.01)
+    }
+
+    fn assert_reversible(name: &str, integrator: Integrator, deviation: Deviation) {
+        assert!(
+            deviation.position < 1e-12 && deviation.velocity < 1e-12,
+            "{}, {:?}: {:?}", name, integrator, deviation,
+        );
+    }
+
+    #[test]
+    fn symmetric_schemes_are_reversible() {
+        for &integrator in [Integrator::Leapfrog, Integrator::Yoshida4, Integrator::ForestRuth].iter() {
+            assert_reversible("biffle", integrator, starting_reversibility::<biffle::Simulation>(integrator, 1000));
+            assert_reversible("rehnberger", integrator, starting_reversibility::<rehnberger::Simulation>(integrator, 1000));
+            assert_reversible("soa", integrator, starting_reversibility::<soa::Simulation>(integrator, 1000));
+            assert_reversible("thaumant", integrator, starting_reversibility::<thaumant::Simulation>(integrator, 1000));
+        }
+    }
+
+    #[test]
+    fn rehnberger_kernels_are_reversible() {
+        use rehnberger::{Kernel, Precision};
+        let kernels = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2];
+        let precisions = [
+            Precision::Approximate { iterations: 0 },
+            Precision::Approximate { iterations: 1 },
+            Precision::Approximate { iterations: 2 },
+            Precision::Exact,
+        ];
+
+        for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+            for &precision in precisions.iter() {
+                let mut sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel)
+                    .with_precision(precision);
+                sim.offset_momentum();
+                let deviation = reversibility(&mut sim, Integrator::Leapfrog, 1000, 0.01);
+                assert_reversible(&format!("{:?}, {:?}", kernel, precision), Integrator::Leapfrog, deviation);
+            }
+        }
+    }
+
+    #[test]
+    fn semi_implicit_euler_is_not_symmetric() {
+        let biffle_deviation = starting_reversibility::<biffle::Simulation>(Integrator::SemiImplicitEuler, 1000);
+        assert!(biffle_deviation.position > 1e-3, "{:?}", biffle_deviation);
+
+        // Still, every implementation lands on the same wrong state.
+        for deviation in [
+            starting_reversibility::<rehnberger::Simulation>(Integrator::SemiImplicitEuler, 1000),
+            starting_reversibility::<soa::Simulation>(Integrator::SemiImplicitEuler, 1000),
+            starting_reversibility::<thaumant::Simulation>(Integrator::SemiImplicitEuler, 1000),
+        ]
+        .iter()
+        {
+            assert!((deviation.position - biffle_deviation.position).abs() < 1e-12, "{:?}", deviation);
+            assert!((deviation.velocity - biffle_deviation.velocity).abs() < 1e-12, "{:?}", deviation);
+        }
+    }
+
+    fn starting_drift<S: NBodySimulator>(steps: usize) -> DriftReport {
+        let mut sim = S::starting_state();
+        sim.offset_momentum();
+        drift_report(&mut sim, Integrator::SemiImplicitEuler, steps, 0.01)
+    }
+
+    #[test]
+    fn two_body_invariants() {
+        let bodies = vec![
+            BodyState { name: String::new(), position: [1., 0., 0.], velocity: [0., 2., 0.], mass: 3. },
+            BodyState { name: String::new(), position: [-3., 0., 0.], velocity: [0., -6., 0.], mass: 1. },
+        ];
+        let invariants = Invariants::of(&bodies);
+        assert_eq!(invariants.momentum, [0., 0., 0.]);
+        assert_eq!(invariants.angular_momentum, [0., 0., 24.]);
+        assert_eq!(invariants.centre_of_mass, CentreOfMass::default());
+        assert_eq!(centre_of_mass(&[]), CentreOfMass::default());
+    }
+
+    #[test]
+    fn offset_momentum_zeroes_momentum() {
+        let mut sim = biffle::Simulation::from_bodies(&body::asteroid_belt(20));
+        sim.offset_momentum();
+        let invariants = Invariants::of(&sim.bodies());
+        for m in 0..3 {
+            assert!(invariants.momentum[m].abs() < 1e-15, "{:?}", invariants);
+            assert!(invariants.centre_of_mass.velocity[m].abs() < 1e-15, "{:?}", invariants);
+        }
+    }
+
+    fn assert_conserved(name: &str, report: &DriftReport) {
+        let max = report.max();
+        assert_eq!(report.steps.len(), 1000);
+        assert!(max.momentum < 1e-13, "{}: {:?}", name, max);
+        assert!(max.angular_momentum < 1e-12, "{}: {:?}", name, max);
+        assert!(max.centre_of_mass_velocity < 1e-14, "{}: {:?}", name, max);
+        assert!(max.energy < 1e-3, "{}: {:?}", name, max);
+    }
+
+    #[test]
+    fn momenta_are_conserved() {
+        assert_conserved("biffle", &starting_drift::<biffle::Simulation>(1000));
+        assert_conserved("rehnberger", &starting_drift::<rehnberger::Simulation>(1000));
+        assert_conserved("soa", &starting_drift::<soa::Simulation>(1000));
+        assert_conserved("thaumant", &starting_drift::<thaumant::Simulation>(1000));
+    }
+
+    #[test]
+    fn rehnberger_kernels_conserve_momenta() {
+        use rehnberger::Kernel;
+        let kernels = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2];
+
+        for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+            let mut sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel);
+            sim.offset_momentum();
+            let report = drift_report(&mut sim, Integrator::SemiImplicitEuler, 1000, 0.01);
+            assert_conserved(&format!("{:?}", kernel), &report);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Reference implementation in double-double arithmetic.
+//!
+//! A `DoubleDouble` is the unevaluated sum of two `f64`s, the second below
+//! half an ulp of the first, which carries about 106 bits of mantissa. The
+//! simulation here keeps positions and velocities and sums the energy in
+//! that precision, so its rounding errors are some 2^-53 times smaller than
+//! the `f64` implementations' over the same steps. Next to them it is as
+//! good as the exact solution of the same integrator, and `divergence`
+//! measures how far each of them has drifted from it.
+//!
+//! The arithmetic follows Hida, Li and Bailey's QD library, with error-free
+//! products by fused multiply-add.
+
+use std::cmp::Ordering;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+use crate::diagnostics::{Deviation, EnergyBreakdown};
+use crate::{BodyState, NBodySimulator};
+
+/// A number `hi + lo` with `|lo| <= ulp(hi) / 2`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DoubleDouble {
+    hi: f64,
+    lo: f64,
+}
+
+/// `a + b` and its rounding error, for any `a` and `b`.
+fn two_sum(a: f64, b: f64) -> DoubleDouble {
+    let hi = a + b;
+    let b_virtual = hi - a;
+    let lo = (a - (hi - b_virtual)) + (b - b_virtual);
+    DoubleDouble { hi, lo }
+}
+
+/// `a + b` and its rounding error, for `|a| >= |b|`.
+fn quick_two_sum(a: f64, b: f64) -> DoubleDouble {
+    let hi = a + b;
+    DoubleDouble { hi, lo: b - (hi - a) }
+}
+
+/// `a * b` and its rounding error.
+fn two_prod(a: f64, b: f64) -> DoubleDouble {
+    let hi = a * b;
+    DoubleDouble { hi, lo: a.mul_add(b, -hi) }
+}
+
+impl DoubleDouble {
+    pub fn new(hi: f64, lo: f64) -> DoubleDouble {
+        quick_two_sum(hi, lo)
+    }
+
+    pub fn hi(self) -> f64 {
+        self.hi
+    }
+
+    pub fn lo(self) -> f64 {
+        self.lo
+    }
+
+    /// The nearest `f64`.
+    pub fn to_f64(self) -> f64 {
+        self.hi + self.lo
+    }
+
+    pub fn sqrt(self) -> DoubleDouble {
+        if self.hi <= 0. {
+            return DoubleDouble::from(self.hi.sqrt());
+        }
+        // One Newton step from the f64 root doubles its precision.
+        let root = self.hi.sqrt();
+        let residual = self - two_prod(root, root);
+        two_sum(root, residual.hi / (2. * root))
+    }
+}
+
+impl From<f64> for DoubleDouble {
+    fn from(x: f64) -> DoubleDouble {
+        DoubleDouble { hi: x, lo: 0. }
+    }
+}
+
+impl PartialOrd for DoubleDouble {
+    fn partial_cmp(&self, other: &DoubleDouble) -> Option<Ordering> {
+        match self.hi.partial_cmp(&other.hi) {
+            Some(Ordering::Equal) => self.lo.partial_cmp(&other.lo),
+            ordering => ordering,
+        }
+    }
+}
+
+impl Neg for DoubleDouble {
+    type Output = DoubleDouble;
+    fn neg(self) -> DoubleDouble {
+        DoubleDouble { hi: -self.hi, lo: -self.lo }
+    }
+}
+
+impl Add for DoubleDouble {
+    type Output = DoubleDouble;
+    fn add(self, rhs: DoubleDouble) -> DoubleDouble {
+        let s = two_sum(self.hi, rhs.hi);
+        let t = two_sum(self.lo, rhs.lo);
+        let s = quick_two_sum(s.hi, s.lo + t.hi);
+        quick_two_sum(s.hi, s.lo + t.lo)
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = DoubleDouble;
+    fn sub(self, rhs: DoubleDouble) -> DoubleDouble {
+        self + -rhs
+    }
+}
+
+impl Mul for DoubleDouble {
+    type Output = DoubleDouble;
+    fn mul(self, rhs: DoubleDouble) -> DoubleDouble {
+        let p = two_prod(self.hi, rhs.hi);
+        quick_two_sum(p.hi, p.lo + (self.hi * rhs.lo + self.lo * rhs.hi))
+    }
+}
+
+impl Div for DoubleDouble {
+    type Output = DoubleDouble;
+    fn div(self, rhs: DoubleDouble) -> DoubleDouble {
+        // Long division, one f64 digit at a time.
+        let q1 = self.hi / rhs.hi;
+        let r = self - rhs * DoubleDouble::from(q1);
+        let q2 = r.hi / rhs.hi;
+        let r = r - rhs * DoubleDouble::from(q2);
+        let q3 = r.hi / rhs.hi;
+        quick_two_sum(q1, q2) + DoubleDouble::from(q3)
+    }
+}
+
+impl AddAssign for DoubleDouble {
+    fn add_assign(&mut self, rhs: DoubleDouble) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for DoubleDouble {
+    fn sub_assign(&mut self, rhs: DoubleDouble) {
+        *self = *self - rhs;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Body {
+    position: [DoubleDouble; 3],
+    velocity: [DoubleDouble; 3],
+    mass: DoubleDouble,
+}
+
+impl From<&BodyState> for Body {
+    fn from(state: &BodyState) -> Body {
+        Body {
+            position: state.position.map(DoubleDouble::from),
+            velocity: state.velocity.map(DoubleDouble::from),
+            mass: DoubleDouble::from(state.mass),
+        }
+    }
+}
+
+/// Rounds to the nearest `f64`s.
+impl From<&Body> for BodyState {
+    fn from(body: &Body) -> BodyState {
+        BodyState {
+            name: String::new(),
+            position: body.position.map(DoubleDouble::to_f64),
+            velocity: body.velocity.map(DoubleDouble::to_f64),
+            mass: body.mass.to_f64(),
+        }
+    }
+}
+
+/// Applies the gravitation between every pair of bodies to their velocities
+/// over a time-step `dt`.
+pub fn kick(bodies: &mut [Body], dt: DoubleDouble) {
+    for i in 0..bodies.len() {
+        let (body1, rest) = bodies[i..].split_first_mut().unwrap();
+        for body2 in rest {
+            let mut d = [DoubleDouble::default(); 3];
+            for (d, (x1, x2)) in d.iter_mut().zip(body1.position.iter().zip(&body2.position)) {
+                *d = *x1 - *x2;
+            }
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+            let mag = dt / (distance_squared * distance_squared.sqrt());
+            for (m, d) in d.iter().enumerate() {
+                body1.velocity[m] -= *d * (body2.mass * mag);
+                body2.velocity[m] += *d * (body1.mass * mag);
+            }
+        }
+    }
+}
+
+/// Moves each body along its velocity over a time-step `dt`.
+pub fn drift(bodies: &mut [Body], dt: DoubleDouble) {
+    for body in bodies {
+        for (x, v) in body.position.iter_mut().zip(&body.velocity) {
+            *x += dt * *v;
+        }
+    }
+}
+
+/// Adjust the Sun's velocity to offset system momentum.
+pub fn offset_momentum(bodies: &mut [Body]) {
+    let solar_mass = DoubleDouble::from(crate::biffle::SOLAR_MASS);
+    if let Some((sun, planets)) = bodies.split_first_mut() {
+        sun.velocity = [DoubleDouble::default(); 3];
+        for planet in planets {
+            for m in 0..3 {
+                sun.velocity[m] -= planet.velocity[m] * planet.mass / solar_mass;
+            }
+        }
+    }
+}
+
+/// Kinetic and potential energy, each summed in double-double.
+pub fn energy(bodies: &[Body]) -> (DoubleDouble, DoubleDouble) {
+    let half = DoubleDouble::from(0.5);
+    let mut kinetic = DoubleDouble::default();
+    let mut potential = DoubleDouble::default();
+    for (i, body1) in bodies.iter().enumerate() {
+        let v = body1.velocity;
+        kinetic += half * body1.mass * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+        for body2 in &bodies[i + 1..] {
+            let mut d = [DoubleDouble::default(); 3];
+            for (d, (x1, x2)) in d.iter_mut().zip(body1.position.iter().zip(&body2.position)) {
+                *d = *x1 - *x2;
+            }
+            potential -= body1.mass * body2.mass / (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+        }
+    }
+    (kinetic, potential)
+}
+
+/// Bodies of a running simulation, behind the common `NBodySimulator`
+/// interface.
+///
+/// Time-steps are taken as `f64`, like every other implementation's, and
+/// body states are rounded to `f64` on the way out. Gravitation is never
+/// softened, so it only serves as the reference of unsoftened runs.
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    bodies: Vec<Body>,
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "double-double";
+
+    type Bodies = Vec<Body>;
+
+    fn new(bodies: Vec<Body>) -> Simulation {
+        Simulation { bodies }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::from_bodies(&crate::body::starting_state())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(bodies.iter().map(Body::from).collect())
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        self.bodies.iter().map(BodyState::from).collect()
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        let dt = DoubleDouble::from(dt);
+        for _ in 0..steps {
+            kick(&mut self.bodies, dt);
+            drift(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        kick(&mut self.bodies, DoubleDouble::from(dt));
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, DoubleDouble::from(dt));
+    }
+
+    fn energy(&self) -> f64 {
+        let (kinetic, potential) = energy(&self.bodies);
+        (kinetic + potential).to_f64()
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        let (kinetic, potential) = energy(&self.bodies);
+        let half = DoubleDouble::from(0.5);
+        EnergyBreakdown {
+            kinetic: kinetic.to_f64(),
+            potential: potential.to_f64(),
+            total: (kinetic + potential).to_f64(),
+            per_body_kinetic: self
+                .bodies
+                .iter()
+                .map(|body| {
+                    let v = body.velocity;
+                    (half * body.mass * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2])).to_f64()
+                })
+                .collect(),
+        }
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.position.map(DoubleDouble::to_f64)).collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.velocity.map(DoubleDouble::to_f64)).collect()
+    }
+}
+
+/// How far an `f64` implementation has drifted from the double-double one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Divergence {
+    pub steps: usize,
+    /// Energy of the implementation minus the reference energy.
+    pub energy: f64,
+    pub deviation: Deviation,
+}
+
+/// Runs `S` and the double-double reference side by side from the Jovian
+/// planets, with time-steps of `dt`, and compares them after each number of
+/// steps in `checkpoints`, which must be increasing.
+pub fn divergence<S: NBodySimulator>(checkpoints: &[usize], dt: f64) -> Vec<Divergence> {
+    let mut sim = S::starting_state();
+    sim.offset_momentum();
+    let mut reference = Simulation::starting_state();
+    reference.offset_momentum();
+
+    let mut step = 0;
+    checkpoints
+        .iter()
+        .map(|&steps| {
+            assert!(steps >= step, "checkpoints must be increasing");
+            sim.advance(steps - step, dt);
+            reference.advance(steps - step, dt);
+            step = steps;
+            Divergence {
+                steps,
+                energy: sim.energy() - reference.energy(),
+                deviation: Deviation::between(&sim.bodies(), &reference.bodies()),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, reference_output, rehnberger, thaumant};
+
+    fn dd(x: f64) -> DoubleDouble {
+        DoubleDouble::from(x)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let tiny = (dd(1.) + dd(1e-20)) - dd(1.);
+        assert_eq!(tiny.to_f64(), 1e-20);
+        assert_eq!(DoubleDouble::new(1., 1e-20).lo(), 1e-20);
+
+        // 1/3 to 32 digits, checked against 3 * 1/3 = 1.
+        let third = dd(1.) / dd(3.);
+        assert_eq!(third.hi(), 1. / 3.);
+        assert!(((third * dd(3.)) - dd(1.)).to_f64().abs() < 1e-31);
+
+        // sqrt(2) = 1.41421356237309504880168872420969807...
+        let root = dd(2.).sqrt();
+        assert_eq!(root.hi(), std::f64::consts::SQRT_2);
+        assert!((root.lo() - -9.667293313452913e-17).abs() < 1e-31, "{:?}", root);
+        assert!((root * root - dd(2.)).to_f64().abs() < 1e-31);
+        assert_eq!(dd(0.).sqrt(), dd(0.));
+
+        assert!(third < dd(1. / 3.) || third > dd(1. / 3.));
+        assert!(DoubleDouble::new(1., 1e-20) > dd(1.));
+        assert_eq!(-third + third, dd(0.));
+    }
+
+    #[test]
+    fn jovian_reference_output() {
+        assert_eq!(reference_output::<Simulation>(1000), "-0.169075164\n-0.169087605\n");
+    }
+
+    fn report<S: NBodySimulator>(checkpoints: &[usize]) -> Vec<Divergence> {
+        let divergences = divergence::<S>(checkpoints, 0.01);
+        // Run with `--nocapture` to see the report.
+        for divergence in &divergences {
+            println!(
+                "{:>12} {:>9} steps: energy {:+.3e}, position {:.3e}, velocity {:.3e}",
+                S::NAME,
+                divergence.steps,
+                divergence.energy,
+                divergence.deviation.position,
+                divergence.deviation.velocity,
+            );
+        }
+        divergences
+    }
+
+    /// Asserts each implementation is within `energy` and `position` of the
+    /// reference at the end of `checkpoints`.
+    fn check(checkpoints: &[usize], energy: f64, position: f64) {
+        for divergence in [
+            report::<biffle::Simulation>(checkpoints),
+            report::<rehnberger::Simulation>(checkpoints),
+            report::<thaumant::Simulation>(checkpoints),
+        ]
+        .iter()
+        .map(|divergences| divergences[divergences.len() - 1])
+        {
+            assert!(divergence.energy.abs() < energy, "{:?}", divergence);
+            assert!(divergence.deviation.position < position, "{:?}", divergence);
+        }
+    }
+
+    #[test]
+    fn divergence_1000() {
+        check(&[1000], 1e-14, 1e-12);
+    }
+
+    // Takes minutes in a debug build, run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn divergence_100_000() {
+        check(&[1000, 100_000], 1e-13, 1e-9);
+    }
+
+    // Takes minutes in a debug build, run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn divergence_1_000_000() {
+        check(&[1000, 100_000, 1_000_000], 1e-13, 1e-8);
+    }
+}
@@ -0,0 +1,165 @@
+//! Symplectic stepping schemes built from an implementation's `kick` and
+//! `drift`.
+//!
+//! Every implementation's `advance` is a semi-implicit Euler step: a kick of
+//! the velocities by the pairwise gravitation followed by a drift of the
+//! positions. The schemes here compose the same two operations with different
+//! weights, so the force kernel of each implementation is reused unchanged and
+//! only the order of accuracy changes.
+
+use crate::NBodySimulator;
+
+/// Stepping scheme, applied to any `NBodySimulator` through its `kick` and
+/// `drift`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    /// Kick then drift, as in each implementation's `advance`; first order.
+    SemiImplicitEuler,
+    /// Kick-drift-kick leapfrog, also known as velocity Verlet; second order.
+    Leapfrog,
+    /// Yoshida's fourth-order triple jump of three leapfrog steps.
+    Yoshida4,
+    /// Forest and Ruth's fourth-order drift-kick-drift scheme.
+    ///
+    /// It shares its coefficients with `Yoshida4`, with the roles of kick and
+    /// drift swapped.
+    ForestRuth,
+}
+
+/// The longest of the triple jump's three sub-steps, `1 / (2 - 2^(1/3))`.
+const W1: f64 = 1.351_207_191_959_657_8;
+/// The backward middle sub-step of the triple jump, `1 - 2 * W1`.
+const W0: f64 = 1. - 2. * W1;
+
+impl Integrator {
+    /// All schemes, from lowest to highest order.
+    pub const ALL: [Integrator; 4] = [
+        Integrator::SemiImplicitEuler,
+        Integrator::Leapfrog,
+        Integrator::Yoshida4,
+        Integrator::ForestRuth,
+    ];
+
+    /// Order of accuracy; the global error scales with `dt` to this power.
+    pub fn order(self) -> u32 {
+        match self {
+            Integrator::SemiImplicitEuler => 1,
+            Integrator::Leapfrog => 2,
+            Integrator::Yoshida4 | Integrator::ForestRuth => 4,
+        }
+    }
+
+    /// Steps `sim` forward by one time-step of length `dt`.
+    pub fn step<S: NBodySimulator>(self, sim: &mut S, dt: f64) {
+        match self {
+            Integrator::SemiImplicitEuler => {
+                sim.kick(dt);
+                sim.drift(dt);
+            }
+            Integrator::Leapfrog => {
+                sim.kick(0.5 * dt);
+                sim.drift(dt);
+                sim.kick(0.5 * dt);
+            }
+            Integrator::Yoshida4 => {
+                sim.kick(0.5 * W1 * dt);
+                sim.drift(W1 * dt);
+                sim.kick(0.5 * (W0 + W1) * dt);
+                sim.drift(W0 * dt);
+                sim.kick(0.5 * (W0 + W1) * dt);
+                sim.drift(W1 * dt);
+                sim.kick(0.5 * W1 * dt);
+            }
+            Integrator::ForestRuth => {
+                sim.drift(0.5 * W1 * dt);
+                sim.kick(W1 * dt);
+                sim.drift(0.5 * (W0 + W1) * dt);
+                sim.kick(W0 * dt);
+                sim.drift(0.5 * (W0 + W1) * dt);
+                sim.kick(W1 * dt);
+                sim.drift(0.5 * W1 * dt);
+            }
+        }
+    }
+
+    /// Steps `sim` forward by `steps` time-steps of length `dt`.
+    pub fn advance<S: NBodySimulator>(self, sim: &mut S, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.step(sim, dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, rehnberger, soa, thaumant};
+
+    /// Largest relative energy error over `time` years of the Jovian planets.
+    fn energy_drift<S: NBodySimulator>(integrator: Integrator, time: f64, dt: f64) -> f64 {
+        let mut sim = S::starting_state();
+        sim.offset_momentum();
+        let initial = sim.energy();
+        let steps = (time / dt).round() as usize;
+        (0..steps)
+            .map(|_| {
+                integrator.step(&mut sim, dt);
+                ((sim.energy() - initial) / initial).abs()
+            })
+            .fold(0., f64::max)
+    }
+
+    #[test]
+    fn coefficients() {
+        assert!((W1 - 1. / (2. - 2f64.cbrt())).abs() < 1e-15);
+        assert!((W0 + 2f64.cbrt() / (2. - 2f64.cbrt())).abs() < 1e-15);
+    }
+
+    #[test]
+    fn euler_matches_advance() {
+        let mut advanced = biffle::Simulation::starting_state();
+        let mut stepped = biffle::Simulation::starting_state();
+        advanced.advance(100, 0.01);
+        Integrator::SemiImplicitEuler.advance(&mut stepped, 100, 0.01);
+        assert_eq!(advanced.bodies(), stepped.bodies());
+    }
+
+    #[test]
+    fn energy_drift_shrinks_with_order() {
+        // Halving the time-step should divide the drift by about `2^order`.
+        for &integrator in Integrator::ALL.iter() {
+            let coarse = energy_drift::<biffle::Simulation>(integrator, 100., 0.2);
+            let fine = energy_drift::<biffle::Simulation>(integrator, 100., 0.1);
+            let expected = 2f64.powi(integrator.order() as i32);
+            assert!(
+                coarse / fine > 0.75 * expected,
+                "{:?}: {:e} / {:e} = {} < {}", integrator, coarse, fine, coarse / fine, 0.75 * expected,
+            );
+        }
+    }
+
+    #[test]
+    fn higher_order_drifts_less() {
+        let errors: Vec<f64> = Integrator::ALL
+            .iter()
+            .map(|&integrator| energy_drift::<biffle::Simulation>(integrator, 100., 0.1))
+            .collect();
+        assert!(errors[1] < errors[0], "{:?}", errors);
+        assert!(errors[2] < errors[1], "{:?}", errors);
+        assert!(errors[3] < errors[1], "{:?}", errors);
+    }
+
+    #[test]
+    fn implementations_agree() {
+        for &integrator in Integrator::ALL.iter() {
+            let biffle_drift = energy_drift::<biffle::Simulation>(integrator, 10., 0.05);
+            let rehnberger_drift = energy_drift::<rehnberger::Simulation>(integrator, 10., 0.05);
+            let soa_drift = energy_drift::<soa::Simulation>(integrator, 10., 0.05);
+            let thaumant_drift = energy_drift::<thaumant::Simulation>(integrator, 10., 0.05);
+
+            for drift in [rehnberger_drift, soa_drift, thaumant_drift].iter() {
+                assert!((drift - biffle_drift).abs() < 1e-12, "{:?}: {} != {}", integrator, drift, biffle_drift);
+            }
+        }
+    }
+}
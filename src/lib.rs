@@ -1,41 +1,482 @@
+pub mod adaptive;
+pub mod barnes_hut;
 pub mod biffle;
+pub mod body;
+pub mod checked;
+pub mod checkpoint;
+pub mod collision;
+pub mod diagnostics;
+pub mod double_double;
+pub mod integrator;
+pub mod observer;
+pub mod parallel;
 pub mod rehnberger;
+pub mod soa;
 pub mod thaumant;
+pub mod trajectory;
+
+pub use body::BodyState;
+
+/// Common interface over the n-body implementations.
+///
+/// Each implementation keeps its own body layout and kernel; this trait only
+/// fixes how a simulation is set up, stepped and inspected, so that tests and
+/// benchmarks can be written once and run against every implementation.
+pub trait NBodySimulator {
+    /// Short name of the implementation, e.g. `"biffle"`.
+    const NAME: &'static str;
+
+    /// Bodies in the layout used by the implementation.
+    type Bodies;
+
+    /// Creates a simulation from an initial state.
+    fn new(bodies: Self::Bodies) -> Self;
+
+    /// Creates a simulation of the Sun and the Jovian planets.
+    fn starting_state() -> Self;
+
+    /// Creates a simulation from implementation-neutral body states.
+    fn from_bodies(bodies: &[BodyState]) -> Self;
+
+    /// Current state of each body.
+    fn bodies(&self) -> Vec<BodyState>;
+
+    /// Replaces the bodies, possibly by a different number of them, keeping
+    /// the settings of the simulation.
+    ///
+    /// By default the simulation is rebuilt with `from_bodies`, which suits
+    /// implementations without settings.
+    fn set_bodies(&mut self, bodies: &[BodyState])
+    where
+        Self: Sized,
+    {
+        *self = Self::from_bodies(bodies);
+    }
+
+    /// Settings beyond the bodies that change how the simulation steps, such
+    /// as a softening length, as space-separated `key=value` pairs; settings
+    /// at their defaults may be left out.
+    ///
+    /// Checkpoints store them, so that a restored simulation steps exactly
+    /// like the one they were taken of.
+    fn settings(&self) -> String {
+        String::new()
+    }
+
+    /// Applies settings as written by `settings`, failing on any it does not
+    /// know.
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        match checkpoint::parse_settings(settings)?.first() {
+            Some((key, _)) => Err(checkpoint::unknown_setting(key)),
+            None => Ok(()),
+        }
+    }
+
+    /// Adjusts the Sun's velocity to offset system momentum.
+    fn offset_momentum(&mut self);
+
+    /// Steps the simulation forward by `steps` time-steps of length `dt`.
+    fn advance(&mut self, steps: usize, dt: f64);
+
+    /// Applies the gravitation between every pair of bodies to their
+    /// velocities over a time-step `dt`, leaving positions unchanged.
+    ///
+    /// One `advance` step is a `kick` followed by a `drift`; the two are
+    /// exposed separately so that `integrator` can compose other schemes.
+    fn kick(&mut self, dt: f64);
+
+    /// Moves each body along its velocity over a time-step `dt`.
+    fn drift(&mut self, dt: f64);
+
+    /// Total energy of the system.
+    fn energy(&self) -> f64;
+
+    /// Kinetic and potential parts of the energy; `total` equals `energy()`.
+    fn energy_breakdown(&self) -> diagnostics::EnergyBreakdown;
+
+    /// Position of each body.
+    fn positions(&self) -> Vec<[f64; 3]>;
+
+    /// Velocity of each body.
+    fn velocities(&self) -> Vec<[f64; 3]>;
+}
+
+/// Energy of the Jovian planets before and after `steps` steps, formatted like
+/// the Benchmarks Game reference output.
+pub fn reference_output<S: NBodySimulator>(steps: usize) -> String {
+    let mut sim = S::starting_state();
+    sim.offset_momentum();
+    let before = sim.energy();
+    sim.advance(steps, 0.01);
+    let after = sim.energy();
+    format!("{:.9}\n{:.9}\n", before, after)
+}
 
 
 #[cfg(test)]
 mod test {
+    use crate::{reference_output, BodyState, NBodySimulator};
+    use crate::body;
+    use crate::diagnostics;
+    use crate::barnes_hut;
     use crate::biffle;
+    use crate::parallel;
     use crate::rehnberger;
+    use crate::soa;
     use crate::thaumant;
 
     fn round12(f: f64) -> f64 {
         (f * 1e12).round() / 1e12
     }
-    
+
+    /// Energy before each of `steps` steps of length `dt`, rounded to 12 decimals.
+    fn energies<S: NBodySimulator>(mut sim: S, steps: usize, dt: f64) -> Vec<f64> {
+        sim.offset_momentum();
+        (0..steps)
+            .map(|_| {
+                let energy = round12(sim.energy());
+                sim.advance(1, dt);
+                energy
+            })
+            .collect()
+    }
+
+    /// Jovian planets with Jupiter swapped for a heavier, closer giant, around
+    /// a lighter Sun that starts out moving.
+    fn custom_system() -> Vec<BodyState> {
+        let mut bodies = body::starting_state();
+        bodies[0].mass *= 0.9;
+        bodies[0].velocity = [0.1, -0.2, 0.05];
+        bodies[1].mass *= 3.;
+        for m in 0..3 {
+            bodies[1].position[m] *= 0.5;
+        }
+        bodies
+    }
+
+    /// Runs `steps` steps of the given system, names dropped from the result.
+    fn simulate<S: NBodySimulator>(bodies: &[BodyState], steps: usize) -> Vec<BodyState> {
+        let mut sim = S::from_bodies(bodies);
+        sim.offset_momentum();
+        sim.advance(steps, 0.01);
+        sim.bodies()
+    }
+
+    fn assert_close(a: &[BodyState], b: &[BodyState]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!(a.mass, b.mass);
+            for m in 0..3 {
+                assert!((a.position[m] - b.position[m]).abs() < 1e-9, "{:?} != {:?}", a, b);
+                assert!((a.velocity[m] - b.velocity[m]).abs() < 1e-9, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    fn round_trip<S: NBodySimulator>() {
+        let bodies: Vec<_> = custom_system()
+            .into_iter()
+            .map(|body| BodyState { name: String::new(), ..body })
+            .collect();
+        assert_eq!(S::from_bodies(&bodies).bodies(), bodies);
+    }
+
+    /// The Jovian planets cut or extended with giants beyond Neptune to `count` bodies.
+    fn planets(count: usize) -> Vec<BodyState> {
+        let mut bodies = body::starting_state();
+        bodies.truncate(count);
+        while bodies.len() < count {
+            let radius = 10. * bodies.len() as f64;
+            let speed = (biffle::SOLAR_MASS / radius).sqrt();
+            bodies.push(BodyState {
+                name: format!("Giant {}", bodies.len()),
+                position: [0., radius, 0.],
+                velocity: [speed, 0., 0.],
+                mass: 1e-3 * biffle::SOLAR_MASS,
+            });
+        }
+        bodies
+    }
+
+    #[test]
+    fn rehnberger_odd_interaction_counts() {
+        use rehnberger::Kernel;
+        let kernels = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2];
+
+        // 6, 15 and 21 interactions.
+        for &count in [4, 6, 7].iter() {
+            let bodies = planets(count);
+            let biffle_energies = energies(biffle::Simulation::from_bodies(&bodies), 1000, 0.01);
+
+            for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+                let bodies = bodies.iter().map(rehnberger::Body::from).collect();
+                let sim = rehnberger::Simulation::with_kernel(bodies, kernel);
+                let rehnberger_energies = energies(sim, 1000, 0.01);
+
+                for (biffle_energy, rehnberger_energy) in biffle_energies.iter().zip(&rehnberger_energies) {
+                    assert!(
+                        (biffle_energy - rehnberger_energy).abs() < 1e-11,
+                        "{} bodies, {:?}: {} != {}", count, kernel, biffle_energy, rehnberger_energy,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Largest difference to `biffle`'s energy over `steps` steps of the Jovian planets.
+    fn max_energy_error<S: NBodySimulator>(mut sim: S, steps: usize) -> f64 {
+        let mut biffle_sim = biffle::Simulation::starting_state();
+        biffle_sim.offset_momentum();
+        sim.offset_momentum();
+        (0..steps)
+            .map(|_| {
+                biffle_sim.advance(1, 0.01);
+                sim.advance(1, 0.01);
+                (sim.energy() - biffle_sim.energy()).abs()
+            })
+            .fold(0., f64::max)
+    }
+
+    #[test]
+    fn rehnberger_precision() {
+        use rehnberger::{Kernel, Precision};
+        let kernels = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2];
+        let precisions = [
+            Precision::Approximate { iterations: 0 },
+            Precision::Approximate { iterations: 1 },
+            Precision::Approximate { iterations: 2 },
+            Precision::Exact,
+        ];
+
+        for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+            let errors: Vec<f64> = precisions
+                .iter()
+                .map(|&precision| {
+                    let sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel)
+                        .with_precision(precision);
+                    max_energy_error(sim, 1000)
+                })
+                .collect();
+
+            assert!(errors.windows(2).all(|pair| pair[1] <= pair[0]), "{:?}: {:?}", kernel, errors);
+            // Each Newton step roughly squares the relative error of the 12-bit
+            // seed, until the rounding of `f64` takes over.
+            assert!(errors[0] > 1e-8 && errors[0] < 1e-6, "{:?}: {:?}", kernel, errors);
+            assert!(errors[1] > 1e-11 && errors[1] < 1e-9, "{:?}: {:?}", kernel, errors);
+            assert!(errors[2] < 1e-14, "{:?}: {:?}", kernel, errors);
+            assert!(errors[3] < 1e-15, "{:?}: {:?}", kernel, errors);
+        }
+
+        // All kernels seed the Newton steps alike on x86_64, and agree to the
+        // last bit whatever the precision.
+        #[cfg(target_arch = "x86_64")]
+        for &precision in precisions.iter() {
+            let run = |kernel| {
+                let mut sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel)
+                    .with_precision(precision);
+                sim.advance(1000, 0.01);
+                sim.bodies()
+            };
+            let scalar = run(Kernel::Scalar);
+            for &kernel in kernels[1..].iter().filter(|kernel| kernel.is_supported()) {
+                assert_eq!(run(kernel), scalar, "{:?}, {:?}", kernel, precision);
+            }
+        }
+    }
+
+    #[test]
+    fn rehnberger_avx2_matches_sse2() {
+        use rehnberger::Kernel;
+        if !Kernel::Avx2.is_supported() {
+            return;
+        }
+
+        // 6, 7 and 12 bodies give 15, 21 and 66 interactions: tails of one
+        // for SSE2 with three or one for AVX2, and of two for AVX2 alone.
+        for &count in [6, 7, 12].iter() {
+            let bodies: Vec<_> = body::asteroid_belt(count).iter().map(rehnberger::Body::from).collect();
+            let mut sse2 = rehnberger::Simulation::with_kernel(bodies.clone(), Kernel::Sse2);
+            let mut avx2 = rehnberger::Simulation::with_kernel(bodies, Kernel::Avx2);
+            sse2.advance(100, 0.01);
+            avx2.advance(100, 0.01);
+
+            assert_eq!(sse2.bodies(), avx2.bodies(), "{} bodies", count);
+        }
+    }
+
+    #[test]
+    fn softening() {
+        use rehnberger::{Kernel, Precision};
+
+        // Two bodies on the same spot, and one passing through them.
+        let bodies = vec![
+            BodyState { name: String::new(), position: [0.; 3], velocity: [0.; 3], mass: 1. },
+            BodyState { name: String::new(), position: [0.; 3], velocity: [0.; 3], mass: 1. },
+            BodyState { name: String::new(), position: [-1., 0., 0.], velocity: [1., 0., 0.], mass: 1e-3 },
+        ];
+        let mut unsoftened = thaumant::Simulation::from_bodies(&bodies);
+        unsoftened.advance(100, 0.01);
+        assert!(unsoftened.energy().is_nan());
+
+        let mut thaumant_sim = thaumant::Simulation::from_bodies(&bodies).with_softening(0.1);
+        let initial = thaumant_sim.energy();
+        assert!((initial - (0.5e-3 - 1. / 0.1 - 2e-3 / 1.01f64.sqrt())).abs() < 1e-12, "{}", initial);
+        thaumant_sim.advance(100, 0.01);
+        let thaumant_bodies = thaumant_sim.bodies();
+        assert!((thaumant_sim.energy() - initial).abs() < 1e-4, "{} != {}", thaumant_sim.energy(), initial);
+
+        let kernels = [Kernel::Scalar, Kernel::Sse2, Kernel::Avx2];
+        for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+            let mut sim = rehnberger::Simulation::with_kernel(bodies.iter().map(rehnberger::Body::from).collect(), kernel)
+                .with_precision(Precision::Exact)
+                .with_softening(0.1);
+            assert_eq!(sim.energy(), initial, "{:?}", kernel);
+            sim.advance(100, 0.01);
+            assert_close(&sim.bodies(), &thaumant_bodies);
+        }
+
+        let mut biffle_sim = biffle::Simulation::from_bodies(&bodies).with_softening(0.1);
+        let mut soa_sim = soa::Simulation::from_bodies(&bodies).with_softening(0.1);
+        let mut parallel_sim = parallel::Simulation::from_bodies(&bodies).with_softening(0.1);
+        let mut barnes_hut_sim = barnes_hut::Simulation::from_bodies(&bodies).with_theta(0.).with_softening(0.1);
+        assert_eq!(biffle_sim.energy(), initial);
+        assert_eq!(soa_sim.energy(), initial);
+        assert_eq!(parallel_sim.energy(), initial);
+        assert_eq!(barnes_hut_sim.energy(), initial);
+        biffle_sim.advance(100, 0.01);
+        soa_sim.advance(100, 0.01);
+        parallel_sim.advance(100, 0.01);
+        barnes_hut_sim.advance(100, 0.01);
+        assert_close(&biffle_sim.bodies(), &thaumant_bodies);
+        assert_eq!(soa_sim.bodies(), biffle_sim.bodies());
+        assert_close(&parallel_sim.bodies(), &thaumant_bodies);
+        assert_close(&barnes_hut_sim.bodies(), &thaumant_bodies);
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        round_trip::<biffle::Simulation>();
+        round_trip::<rehnberger::Simulation>();
+        round_trip::<soa::Simulation>();
+        round_trip::<thaumant::Simulation>();
+    }
+
+    #[test]
+    fn compare_custom_system() {
+        let bodies = custom_system();
+        let biffle_bodies     = simulate::<biffle::Simulation>(&bodies, 1000);
+        let rehnberger_bodies = simulate::<rehnberger::Simulation>(&bodies, 1000);
+        let soa_bodies        = simulate::<soa::Simulation>(&bodies, 1000);
+        let thaumant_bodies   = simulate::<thaumant::Simulation>(&bodies, 1000);
+
+        assert_close(&biffle_bodies, &rehnberger_bodies);
+        assert_eq!(biffle_bodies, soa_bodies);
+        assert_close(&biffle_bodies, &thaumant_bodies);
+    }
+
+    #[test]
+    fn compare_many_bodies() {
+        // 48 bodies give 1128 interactions, more than `gcc #4` buffers hold.
+        let bodies = body::asteroid_belt(48);
+        let biffle_bodies     = simulate::<biffle::Simulation>(&bodies, 100);
+        let rehnberger_bodies = simulate::<rehnberger::Simulation>(&bodies, 100);
+        let soa_bodies        = simulate::<soa::Simulation>(&bodies, 100);
+        let thaumant_bodies   = simulate::<thaumant::Simulation>(&bodies, 100);
+
+        assert_close(&biffle_bodies, &rehnberger_bodies);
+        assert_eq!(biffle_bodies, soa_bodies);
+        assert_close(&biffle_bodies, &thaumant_bodies);
+    }
+
     #[test]
     fn compare() {
-        let mut biffle_bodies     = biffle::STARTING_STATE;
-        let mut rehnberger_bodies = rehnberger::STARTING_STATE;
-        let mut thaumant_bodies   = thaumant::STARTING_STATE;
+        for &dt in [0.01, 0.001, 0.05, -0.01, -0.05].iter() {
+            let biffle_energies     = energies(biffle::Simulation::starting_state(), 1000, dt);
+            let rehnberger_energies = energies(rehnberger::Simulation::starting_state(), 1000, dt);
+            let soa_energies        = energies(soa::Simulation::starting_state(), 1000, dt);
+            let thaumant_energies   = energies(thaumant::Simulation::starting_state(), 1000, dt);
+
+            // At the benchmark's step the outputs agree exactly; at other
+            // steps rehnberger's approximate inverse square root can tip the
+            // last rounded digit.
+            if dt == 0.01 {
+                assert_eq!(biffle_energies, rehnberger_energies, "dt = {}", dt);
+            }
+            for (biffle_energy, rehnberger_energy) in biffle_energies.iter().zip(&rehnberger_energies) {
+                assert!(
+                    (biffle_energy - rehnberger_energy).abs() < 1.5e-12,
+                    "dt = {}: {} != {}", dt, biffle_energy, rehnberger_energy,
+                );
+            }
+            assert_eq!(biffle_energies, soa_energies, "dt = {}", dt);
+            assert_eq!(biffle_energies, thaumant_energies, "dt = {}", dt);
+        }
+    }
 
-        let mut rehnberger_sim = rehnberger::BodiesAdvance::new();
+    fn breakdown<S: NBodySimulator>() -> diagnostics::EnergyBreakdown {
+        let mut sim = S::starting_state();
+        sim.offset_momentum();
+        sim.advance(1000, 0.01);
+        let breakdown = sim.energy_breakdown();
+        assert_eq!(breakdown.total, sim.energy(), "{}", S::NAME);
+        breakdown
+    }
 
-        biffle::offset_momentum(&mut biffle_bodies);
-        rehnberger::offset_momentum(&mut rehnberger_bodies);
-        thaumant::offset_momentum(&mut thaumant_bodies);
+    #[test]
+    fn compare_energy_breakdown() {
+        let biffle_breakdown = breakdown::<biffle::Simulation>();
+        let per_body_sum: f64 = biffle_breakdown.per_body_kinetic.iter().sum();
+        assert_eq!(biffle_breakdown.per_body_kinetic.len(), 5);
+        assert!((per_body_sum - biffle_breakdown.kinetic).abs() < 1e-15);
+        assert!((biffle_breakdown.kinetic + biffle_breakdown.potential - biffle_breakdown.total).abs() < 1e-15);
+        // The planets' orbits are nearly circular.
+        assert!((biffle_breakdown.virial_ratio() - 1.).abs() < 0.1, "{:?}", biffle_breakdown);
 
-        for _ in 0..1000 {
-            let biffle_energy     = round12(biffle::compute_energy(&mut biffle_bodies));
-            let rehnberger_energy = round12(rehnberger::compute_energy(&mut rehnberger_bodies));
-            let thaumant_energy   = round12(thaumant::compute_energy(&mut thaumant_bodies));
+        for other in [
+            breakdown::<rehnberger::Simulation>(),
+            breakdown::<soa::Simulation>(),
+            breakdown::<thaumant::Simulation>(),
+        ]
+        .iter()
+        {
+            assert!((other.kinetic - biffle_breakdown.kinetic).abs() < 1e-12, "{:?}", other);
+            assert!((other.potential - biffle_breakdown.potential).abs() < 1e-12, "{:?}", other);
+            for (a, b) in other.per_body_kinetic.iter().zip(&biffle_breakdown.per_body_kinetic) {
+                assert!((a - b).abs() < 1e-12, "{:?}", other);
+            }
+        }
+    }
 
-            assert_eq!(biffle_energy, rehnberger_energy);
-            assert_eq!(biffle_energy, thaumant_energy);
+    #[test]
+    fn compare_rehnberger_kernels() {
+        let biffle_energies = energies(biffle::Simulation::starting_state(), 1000, 0.01);
+        let kernels = [rehnberger::Kernel::Scalar, rehnberger::Kernel::Sse2, rehnberger::Kernel::Avx2];
 
-            biffle::advance(&mut biffle_bodies);
-            rehnberger_sim.advance(&mut rehnberger_bodies, 0.01);
-            thaumant::advance(&mut thaumant_bodies, 1);
+        for &kernel in kernels.iter().filter(|kernel| kernel.is_supported()) {
+            let sim = rehnberger::Simulation::with_kernel(rehnberger::STARTING_STATE.to_vec(), kernel);
+            assert_eq!(energies(sim, 1000, 0.01), biffle_energies, "{:?}", kernel);
         }
     }
+
+    // Expected outputs published with the Benchmarks Game nbody programs.
+    const OUTPUT_1000: &str = "-0.169075164\n-0.169087605\n";
+    const OUTPUT_50_000_000: &str = "-0.169075164\n-0.169059907\n";
+
+    #[test]
+    fn reference_output_1000() {
+        assert_eq!(reference_output::<biffle::Simulation>(1000), OUTPUT_1000);
+        assert_eq!(reference_output::<rehnberger::Simulation>(1000), OUTPUT_1000);
+        assert_eq!(reference_output::<soa::Simulation>(1000), OUTPUT_1000);
+        assert_eq!(reference_output::<thaumant::Simulation>(1000), OUTPUT_1000);
+    }
+
+    // Takes minutes in a debug build, run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn reference_output_50_000_000() {
+        assert_eq!(reference_output::<biffle::Simulation>(50_000_000), OUTPUT_50_000_000);
+        assert_eq!(reference_output::<rehnberger::Simulation>(50_000_000), OUTPUT_50_000_000);
+        assert_eq!(reference_output::<soa::Simulation>(50_000_000), OUTPUT_50_000_000);
+        assert_eq!(reference_output::<thaumant::Simulation>(50_000_000), OUTPUT_50_000_000);
+    }
 }
@@ -0,0 +1,147 @@
+//! Read-only access to a running simulation.
+//!
+//! `NBodySimulator`'s inspection methods all take `&self`, so diagnostics can
+//! share a simulation between steps, or from several threads at once. A
+//! `Snapshot` copies out everything they usually need, and an `Observer` is
+//! called between the steps of `advance_observed`.
+
+use crate::integrator::Integrator;
+use crate::NBodySimulator;
+
+/// Copy of a simulation's state after a given number of steps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Steps taken when the snapshot was taken.
+    pub step: usize,
+    pub energy: f64,
+    pub positions: Vec<[f64; 3]>,
+    pub velocities: Vec<[f64; 3]>,
+}
+
+impl Snapshot {
+    pub fn of<S: NBodySimulator>(sim: &S, step: usize) -> Snapshot {
+        Snapshot {
+            step,
+            energy: sim.energy(),
+            positions: sim.positions(),
+            velocities: sim.velocities(),
+        }
+    }
+}
+
+/// Inspects a simulation between steps, without being able to change it.
+pub trait Observer<S: NBodySimulator> {
+    /// Called with the number of steps taken so far.
+    fn observe(&mut self, step: usize, sim: &S);
+}
+
+impl<S: NBodySimulator, F: FnMut(usize, &S)> Observer<S> for F {
+    fn observe(&mut self, step: usize, sim: &S) {
+        self(step, sim)
+    }
+}
+
+/// Collects a `Snapshot` each time it observes the simulation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recorder {
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl<S: NBodySimulator> Observer<S> for Recorder {
+    fn observe(&mut self, step: usize, sim: &S) {
+        self.snapshots.push(Snapshot::of(sim, step));
+    }
+}
+
+/// Steps `sim` forward by `steps` steps of `dt` with `integrator`, letting
+/// `observer` see the initial state and the state after every `every`th step.
+///
+/// The final state is always observed, even when `steps` is not a multiple
+/// of `every`.
+pub fn advance_observed<S, O>(sim: &mut S, integrator: Integrator, steps: usize, dt: f64, every: usize, observer: &mut O)
+where
+    S: NBodySimulator,
+    O: Observer<S>,
+{
+    assert!(every > 0, "observation interval must be positive");
+    observer.observe(0, sim);
+    for step in 1..=steps {
+        integrator.step(sim, dt);
+        if step % every == 0 || step == steps {
+            observer.observe(step, sim);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::{biffle, rehnberger, soa, thaumant};
+
+    #[test]
+    fn observes_every_interval() {
+        let mut sim = biffle::Simulation::starting_state();
+        let mut recorder = Recorder::default();
+        advance_observed(&mut sim, Integrator::SemiImplicitEuler, 25, 0.01, 10, &mut recorder);
+
+        let steps: Vec<usize> = recorder.snapshots.iter().map(|snapshot| snapshot.step).collect();
+        assert_eq!(steps, [0, 10, 20, 25]);
+        assert_eq!(recorder.snapshots[3], Snapshot::of(&sim, 25));
+    }
+
+    #[test]
+    fn observing_does_not_change_the_run() {
+        let mut observed = thaumant::Simulation::starting_state();
+        let mut plain = thaumant::Simulation::starting_state();
+        let mut energies = Vec::new();
+        advance_observed(&mut observed, Integrator::SemiImplicitEuler, 100, 0.01, 1, &mut |_, sim: &thaumant::Simulation| {
+            energies.push(sim.energy())
+        });
+        plain.advance(100, 0.01);
+
+        assert_eq!(energies.len(), 101);
+        assert_eq!(observed.bodies(), plain.bodies());
+    }
+
+    fn concurrent_readers<S: NBodySimulator + Sync>() {
+        let sim = S::starting_state();
+        let (energy, positions) = thread::scope(|scope| {
+            let energy = scope.spawn(|| sim.energy());
+            let positions = scope.spawn(|| sim.positions());
+            (energy.join().unwrap(), positions.join().unwrap())
+        });
+        assert_eq!(energy, sim.energy());
+        assert_eq!(positions, sim.positions());
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        concurrent_readers::<biffle::Simulation>();
+        concurrent_readers::<rehnberger::Simulation>();
+        concurrent_readers::<soa::Simulation>();
+        concurrent_readers::<thaumant::Simulation>();
+    }
+
+    #[test]
+    fn snapshots_to_another_thread() {
+        let (sender, receiver) = mpsc::channel();
+        let monitor = thread::spawn(move || {
+            let snapshots: Vec<Snapshot> = receiver.iter().collect();
+            let initial = snapshots[0].energy;
+            snapshots.iter().map(|snapshot| (snapshot.energy - initial).abs()).fold(0., f64::max)
+        });
+
+        let mut sim = soa::Simulation::starting_state();
+        sim.offset_momentum();
+        advance_observed(&mut sim, Integrator::Leapfrog, 1000, 0.01, 100, &mut |step, sim: &soa::Simulation| {
+            sender.send(Snapshot::of(sim, step)).unwrap()
+        });
+        drop(sender);
+
+        let drift = monitor.join().unwrap();
+        assert!(drift > 0. && drift < 1e-5, "{}", drift);
+    }
+}
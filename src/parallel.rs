@@ -0,0 +1,530 @@
+//! Multithreaded all-pairs kernel for large systems.
+//!
+//! Each unique pair of bodies is evaluated once, as in the serial kernels.
+//! The pairs `i < j` are split by rows `i` into blocks holding about as many
+//! pairs each, and every block adds up its accelerations in a buffer of its
+//! own. The blocks are then reduced in block order, so results are
+//! bit-identical whatever the number of threads: the blocks only depend on
+//! the number of bodies, and the threads only decide which blocks run at
+//! once.
+//!
+//! Each buffer holds an acceleration for every body, so the blocks take up
+//! to `64 * 24 * n` bytes between them: about 150 MB for 100 000 bodies.
+//!
+//! A `Simulation` keeps its worker threads and buffers from one step to the
+//! next; the threads are woken up by a message for every kick, and stopped
+//! when the simulation is dropped. Threads scoped to each kick would be
+//! simpler, but would be spawned and joined again on every step, which
+//! weighs on the kicks of smaller systems.
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+use crate::checkpoint::{parse_softening, softening_settings};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
+/// State of every body in the system.
+#[derive(Clone, Debug, Default)]
+pub struct Bodies {
+    positions: Vec<[f64; 3]>,
+    velocities: Vec<[f64; 3]>,
+    masses: Vec<f64>,
+}
+
+impl Bodies {
+    pub fn len(&self) -> usize {
+        self.masses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masses.is_empty()
+    }
+}
+
+impl From<&[BodyState]> for Bodies {
+    fn from(states: &[BodyState]) -> Bodies {
+        Bodies {
+            positions: states.iter().map(|state| state.position).collect(),
+            velocities: states.iter().map(|state| state.velocity).collect(),
+            masses: states.iter().map(|state| state.mass).collect(),
+        }
+    }
+}
+
+impl From<&Bodies> for Vec<BodyState> {
+    fn from(bodies: &Bodies) -> Vec<BodyState> {
+        (0..bodies.len())
+            .map(|i| BodyState {
+                name: String::new(),
+                position: bodies.positions[i],
+                velocity: bodies.velocities[i],
+                mass: bodies.masses[i],
+            })
+            .collect()
+    }
+}
+
+/// Number of threads the system can run in parallel, or 1 if unknown.
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Largest number of blocks the pairs are split into.
+const MAX_BLOCKS: usize = 64;
+
+/// First row of each block of the pair triangle `i < j` of `n` bodies,
+/// followed by `n`: block `b` holds the rows `bounds[b]..bounds[b + 1]`.
+///
+/// The blocks hold about as many pairs each; some are empty with few bodies.
+fn block_bounds(n: usize, bounds: &mut Vec<usize>) {
+    let pairs = n * n.saturating_sub(1) / 2;
+    let blocks = MAX_BLOCKS.min(n.max(1));
+    bounds.clear();
+    bounds.push(0);
+    let (mut row, mut rows_pairs) = (0, 0);
+    for b in 1..blocks {
+        while rows_pairs < pairs * b / blocks {
+            rows_pairs += n - 1 - row;
+            row += 1;
+        }
+        bounds.push(row);
+    }
+    bounds.push(n);
+}
+
+/// Bodies and blocks of the step under way.
+#[derive(Default)]
+struct Step {
+    positions: Vec<[f64; 3]>,
+    masses: Vec<f64>,
+    softening: f64,
+    bounds: Vec<usize>,
+}
+
+impl Step {
+    /// Adds the accelerations between the pairs in `rows` to `accelerations`,
+    /// with the distances softened if `SOFTENED`.
+    fn accelerate_rows<const SOFTENED: bool>(&self, rows: Range<usize>, accelerations: &mut [[f64; 3]]) {
+        let (positions, masses) = (&self.positions, &self.masses);
+        let softening_squared = self.softening * self.softening;
+        for i in rows {
+            for j in i + 1..positions.len() {
+                let (a, b) = (positions[i], positions[j]);
+                let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+                let mut distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+                if SOFTENED {
+                    distance_squared += softening_squared;
+                }
+                let mag = 1. / (distance_squared * distance_squared.sqrt());
+                for m in 0..3 {
+                    accelerations[i][m] -= d[m] * masses[j] * mag;
+                    accelerations[j][m] += d[m] * masses[i] * mag;
+                }
+            }
+        }
+    }
+}
+
+/// State the threads of a `Pool` share: the step under way and the
+/// accelerations added up by each block.
+#[derive(Default)]
+struct Shared {
+    step: RwLock<Step>,
+    accelerations: Vec<Mutex<Vec<[f64; 3]>>>,
+    /// Next block for a thread to take.
+    next: AtomicUsize,
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            accelerations: (0..MAX_BLOCKS).map(|_| Mutex::default()).collect(),
+            ..Shared::default()
+        }
+    }
+
+    /// Takes blocks until there are none left.
+    fn run_blocks(&self) {
+        let step = self.step.read().unwrap();
+        loop {
+            let b = self.next.fetch_add(1, Ordering::Relaxed);
+            if b + 1 >= step.bounds.len() {
+                return;
+            }
+            let mut accelerations = self.accelerations[b].lock().unwrap();
+            accelerations.clear();
+            accelerations.resize(step.positions.len(), [0.; 3]);
+            let rows = step.bounds[b]..step.bounds[b + 1];
+            // Leave out the softening when there is none.
+            if step.softening != 0. {
+                step.accelerate_rows::<true>(rows, &mut accelerations);
+            } else {
+                step.accelerate_rows::<false>(rows, &mut accelerations);
+            }
+        }
+    }
+}
+
+/// Worker threads kept across steps, along with the calling thread.
+struct Pool {
+    shared: Arc<Shared>,
+    /// Wakes up each worker for a kick; dropping it stops the worker.
+    workers: Vec<(Sender<()>, JoinHandle<()>)>,
+    /// Receives from each worker done with a kick whether it succeeded.
+    done: Receiver<bool>,
+}
+
+impl Pool {
+    /// Pool of `threads` threads, the calling one included.
+    fn new(threads: usize) -> Pool {
+        let shared = Arc::new(Shared::new());
+        let (done_sender, done) = mpsc::channel();
+        let workers = (1..threads)
+            .map(|_| {
+                let (wake, woken) = mpsc::channel::<()>();
+                let (shared, done) = (Arc::clone(&shared), done_sender.clone());
+                let handle = thread::spawn(move || {
+                    for () in woken {
+                        let ran = panic::catch_unwind(AssertUnwindSafe(|| shared.run_blocks()));
+                        if done.send(ran.is_ok()).is_err() {
+                            return;
+                        }
+                    }
+                });
+                (wake, handle)
+            })
+            .collect();
+        Pool { shared, workers, done }
+    }
+
+    fn threads(&self) -> usize {
+        self.workers.len() + 1
+    }
+
+    fn kick(&mut self, bodies: &mut Bodies, dt: f64, softening: f64) {
+        let n = bodies.len();
+        let shared = &*self.shared;
+        {
+            let mut step = shared.step.write().unwrap();
+            step.positions.clear();
+            step.positions.extend_from_slice(&bodies.positions);
+            step.masses.clear();
+            step.masses.extend_from_slice(&bodies.masses);
+            step.softening = softening;
+            if step.bounds.last() != Some(&n) {
+                block_bounds(n, &mut step.bounds);
+            }
+        }
+        shared.next.store(0, Ordering::Relaxed);
+
+        for (wake, _) in &self.workers {
+            wake.send(()).expect("parallel worker thread stopped");
+        }
+        shared.run_blocks();
+        // Wait for every worker before reporting a failure, so that none is
+        // left running the blocks of this kick.
+        let succeeded = self.workers.iter().fold(true, |succeeded, _| self.done.recv().unwrap() && succeeded);
+        assert!(succeeded, "parallel worker thread panicked");
+
+        // Reduce the blocks in order.
+        let blocks = shared.step.read().unwrap().bounds.len() - 1;
+        let accelerations: Vec<_> = shared.accelerations[..blocks].iter().map(|a| a.lock().unwrap()).collect();
+        for (k, velocity) in bodies.velocities.iter_mut().enumerate() {
+            let mut acceleration = [0.; 3];
+            for block in &accelerations {
+                for m in 0..3 {
+                    acceleration[m] += block[k][m];
+                }
+            }
+            for m in 0..3 {
+                velocity[m] += dt * acceleration[m];
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for (wake, handle) in self.workers.drain(..) {
+            drop(wake);
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool").field("threads", &self.threads()).finish()
+    }
+}
+
+/// Applies the gravitation between every pair of bodies to their velocities
+/// over a time-step `dt`, on the calling thread.
+///
+/// The result is the same as that of a `Simulation` on any number of
+/// threads, which also keeps its buffers across steps.
+pub fn kick(bodies: &mut Bodies, dt: f64) {
+    Pool::new(1).kick(bodies, dt, 0.);
+}
+
+/// Moves each body along its velocity over a time-step `dt`.
+pub fn drift(bodies: &mut Bodies, dt: f64) {
+    for (position, velocity) in bodies.positions.iter_mut().zip(&bodies.velocities) {
+        for (x, v) in position.iter_mut().zip(velocity) {
+            *x += dt * v;
+        }
+    }
+}
+
+/// Adjust the Sun's velocity to offset system momentum.
+pub fn offset_momentum(bodies: &mut Bodies) {
+    if bodies.is_empty() {
+        return;
+    }
+    let mut sun = [0.; 3];
+    for (velocity, mass) in bodies.velocities.iter().zip(&bodies.masses).skip(1) {
+        for m in 0..3 {
+            sun[m] -= velocity[m] * mass / crate::biffle::SOLAR_MASS;
+        }
+    }
+    bodies.velocities[0] = sun;
+}
+
+/// Compute the system energy.
+pub fn compute_energy(bodies: &Bodies) -> f64 {
+    softened_energy(bodies, 0.)
+}
+
+/// Compute the system energy with the potential softened by `softening`.
+pub fn softened_energy(bodies: &Bodies, softening: f64) -> f64 {
+    let mut energy = 0.;
+    add_energy(bodies, softening, &mut energy);
+    energy
+}
+
+/// Compute the system energy, split into kinetic and potential parts.
+pub fn energy_breakdown(bodies: &Bodies) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.)
+}
+
+/// Compute the parts of the system energy, with the potential softened by
+/// `softening`.
+pub fn softened_energy_breakdown(bodies: &Bodies, softening: f64) -> EnergyBreakdown {
+    let mut breakdown = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut breakdown);
+    breakdown
+}
+
+fn add_energy<E: EnergyTerms>(bodies: &Bodies, softening: f64, energy: &mut E) {
+    for i in 0..bodies.len() {
+        let v = bodies.velocities[i];
+        energy.add_kinetic(0.5 * bodies.masses[i] * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]));
+        for j in i + 1..bodies.len() {
+            let (a, b) = (bodies.positions[i], bodies.positions[j]);
+            let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+            let distance_squared = d[0] * d[0] + d[1] * d[1] + d[2] * d[2] + softening * softening;
+            energy.add_potential(-bodies.masses[i] * bodies.masses[j] / distance_squared.sqrt());
+        }
+    }
+}
+
+/// Bodies of a running simulation and the threads to step them on, behind
+/// the common `NBodySimulator` interface.
+#[derive(Debug)]
+pub struct Simulation {
+    bodies: Bodies,
+    softening: f64,
+    pool: Pool,
+}
+
+impl Simulation {
+    /// Softens gravitation with a Plummer length `softening`, 0 by default.
+    pub fn with_softening(self, softening: f64) -> Simulation {
+        Simulation { softening, ..self }
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.softening
+    }
+
+    /// Sets the number of threads, `available_threads()` by default.
+    pub fn with_threads(self, threads: usize) -> Simulation {
+        assert!(threads > 0, "need at least one thread");
+        Simulation { pool: Pool::new(threads), ..self }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.threads()
+    }
+}
+
+/// A clone runs on threads of its own.
+impl Clone for Simulation {
+    fn clone(&self) -> Simulation {
+        Simulation {
+            bodies: self.bodies.clone(),
+            softening: self.softening,
+            pool: Pool::new(self.threads()),
+        }
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "parallel";
+
+    type Bodies = Bodies;
+
+    fn new(bodies: Bodies) -> Simulation {
+        Simulation { bodies, softening: 0., pool: Pool::new(available_threads()) }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::from_bodies(&crate::body::starting_state())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(Bodies::from(bodies))
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        Vec::from(&self.bodies)
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        self.bodies = Bodies::from(bodies);
+    }
+
+    fn settings(&self) -> String {
+        softening_settings(self.softening)
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        self.softening = parse_softening(settings)?;
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.pool.kick(&mut self.bodies, dt, self.softening);
+            drift(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.pool.kick(&mut self.bodies, dt, self.softening);
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, dt);
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.softening)
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.softening)
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.positions.clone()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.velocities.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, body, reference_output};
+
+    #[test]
+    fn thread_count_does_not_change_results() {
+        let bodies = body::asteroid_belt(101);
+        let run = |threads| {
+            let mut sim = Simulation::from_bodies(&bodies).with_threads(threads);
+            sim.offset_momentum();
+            sim.advance(20, 0.01);
+            sim.bodies()
+        };
+        let serial = run(1);
+        for &threads in [2, 3, 4, 7, 101, 200].iter() {
+            assert_eq!(run(threads), serial, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn blocks_are_balanced() {
+        let mut bounds = Vec::new();
+        block_bounds(10_000, &mut bounds);
+        assert_eq!(bounds.len(), MAX_BLOCKS + 1);
+        assert_eq!((bounds[0], bounds[MAX_BLOCKS]), (0, 10_000));
+        let pairs = |rows: &[usize]| (rows[0]..rows[1]).map(|i| 10_000 - 1 - i).sum::<usize>();
+        let per_block = 10_000 * 9_999 / 2 / MAX_BLOCKS;
+        for rows in bounds.windows(2) {
+            assert!(pairs(rows).abs_diff(per_block) < 10_000, "{:?}", rows);
+        }
+
+        // Fewer bodies than blocks leave some blocks empty.
+        block_bounds(5, &mut bounds);
+        assert_eq!(bounds, [0, 1, 1, 2, 3, 5]);
+        block_bounds(0, &mut bounds);
+        assert_eq!(bounds, [0, 0]);
+    }
+
+    #[test]
+    fn free_kick_matches_simulation() {
+        let bodies = body::asteroid_belt(101);
+        let mut sim = Simulation::from_bodies(&bodies).with_threads(3);
+        assert_eq!(sim.threads(), 3);
+        let mut free = Bodies::from(&bodies[..]);
+        for _ in 0..10 {
+            sim.kick(0.01);
+            sim.drift(0.01);
+            kick(&mut free, 0.01);
+            drift(&mut free, 0.01);
+        }
+        assert_eq!(sim.bodies(), Vec::from(&free));
+        assert_eq!(sim.clone().bodies(), sim.bodies());
+        assert_eq!(sim.clone().threads(), 3);
+    }
+
+    #[test]
+    fn matches_biffle() {
+        let bodies = body::asteroid_belt(48);
+        let mut parallel = Simulation::from_bodies(&bodies).with_threads(4);
+        let mut serial = biffle::Simulation::from_bodies(&bodies);
+        parallel.offset_momentum();
+        serial.offset_momentum();
+        parallel.advance(100, 0.01);
+        serial.advance(100, 0.01);
+
+        for (a, b) in parallel.bodies().iter().zip(serial.bodies()) {
+            for m in 0..3 {
+                assert!((a.position[m] - b.position[m]).abs() < 1e-12, "{:?} != {:?}", a, b);
+                assert!((a.velocity[m] - b.velocity[m]).abs() < 1e-12, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn jovian_reference_output() {
+        assert_eq!(reference_output::<Simulation>(1000), "-0.169075164\n-0.169087605\n");
+        let mut sim = Simulation::from_bodies(&[]);
+        sim.advance(1, 0.01);
+        assert!(sim.bodies().is_empty());
+    }
+}
@@ -7,8 +7,10 @@
 // converted from c++ to c, by Alexey Medvedchikov
 // converted from c to Rust by Frank Rehberger
 //
-// As the code of `gcc #4` this code requires hardware supporting
-// the CPU feature SSE2, implementing SIMD operations.
+// As the code of `gcc #4` this code uses the CPU feature SSE2,
+// implementing SIMD operations, where available. On other targets, or
+// when selected explicitly, a portable scalar kernel is used instead.
+// Where AVX2 is available, a wider variant of the SSE2 kernel is used.
 //
 // As for `gcc` the operation symbols `*` and `+` etc. are overloaded
 // for SIMD data type _m128f (2x double float SIMD data type); the 
@@ -21,13 +23,25 @@
 // requires a different layout; the comment is referring to the 
 // corresponding expression in `gcc #4`
 
-const PI: f64 = 3.141592653589793;
+// The digits of the starting state and the index loops are kept as in `gcc #4`.
+#![allow(clippy::excessive_precision, clippy::needless_range_loop)]
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
 const SOLAR_MASS: f64 = 4.0 * PI * PI;
 const YEAR: f64 = 365.24;
+// Number of bodies in the starting state
 const N_BODIES: usize = 5;
 
+#[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
+use crate::checkpoint::{parse_setting, parse_settings, unknown_setting};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
 // Datatype respresenting a Planet
 //
 // The annotation `repr(C)` prevents the Rust-compiler from any
@@ -48,6 +62,31 @@ pub struct Body {
     mass: f64,
 }
 
+// Conversion from the implementation-neutral body state, the `fill` padding
+// is zeroed
+impl From<&BodyState> for Body {
+    fn from(state: &BodyState) -> Body {
+        Body {
+            x: state.position,
+            fill: 0.0,
+            v: state.velocity,
+            mass: state.mass,
+        }
+    }
+}
+
+// Conversion into the implementation-neutral body state
+impl From<&Body> for BodyState {
+    fn from(body: &Body) -> BodyState {
+        BodyState {
+            name: String::new(),
+            position: body.x,
+            velocity: body.v,
+            mass: body.mass,
+        }
+    }
+}
+
 pub static STARTING_STATE: [Body; N_BODIES] = [
     // Sun
     Body {
@@ -144,8 +183,12 @@ impl Default for Delta {
 }
 
 // Calculating the offset momentum
-pub fn offset_momentum(bodies: &mut [Body; N_BODIES]) {
-    for i in 0..bodies.len() {
+pub fn offset_momentum(bodies: &mut [Body]) {
+    if bodies.is_empty() {
+        return;
+    }
+    bodies[0].v = [0.0; 3];
+    for i in 1..bodies.len() {
         for k in 0..3 {
             bodies[0].v[k] -= bodies[i].v[k] * bodies[i].mass / SOLAR_MASS;
         }
@@ -153,42 +196,55 @@ pub fn offset_momentum(bodies: &mut [Body; N_BODIES]) {
 }
 
 // Calculating the energy of the N body system
-pub fn compute_energy(bodies: &[Body; N_BODIES]) -> f64 {
-    let mut dx: [f64; 3] = [0.0; 3];
+pub fn compute_energy(bodies: &[Body]) -> f64 {
+    softened_energy(bodies, 0.0)
+}
+
+// Calculating the energy, the potential being softened by the Plummer length
+// `softening`
+pub fn softened_energy(bodies: &[Body], softening: f64) -> f64 {
     let mut e = 0.0;
+    add_energy(bodies, softening, &mut e);
+    e
+}
+
+// Calculating the kinetic and potential parts of the energy separately
+pub fn energy_breakdown(bodies: &[Body]) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.0)
+}
+
+// Calculating the parts of the energy, the potential being softened by the
+// Plummer length `softening`
+pub fn softened_energy_breakdown(bodies: &[Body], softening: f64) -> EnergyBreakdown {
+    let mut e = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut e);
+    e
+}
+
+// Adding up the terms of the energy, for the total alone or for its parts
+fn add_energy<E: EnergyTerms>(bodies: &[Body], softening: f64, e: &mut E) {
+    let mut dx: [f64; 3] = [0.0; 3];
 
     for i in 0..bodies.len() {
-        e += bodies[i].mass
-            * ((bodies[i].v[0] * bodies[i].v[0])
-                + (bodies[i].v[1] * bodies[i].v[1])
-                + (bodies[i].v[2] * bodies[i].v[2]))
-            / 2.0;
+        e.add_kinetic(
+            bodies[i].mass
+                * ((bodies[i].v[0] * bodies[i].v[0])
+                    + (bodies[i].v[1] * bodies[i].v[1])
+                    + (bodies[i].v[2] * bodies[i].v[2]))
+                / 2.0,
+        );
 
         for j in (i + 1)..bodies.len() {
             for k in 0..3 {
                 dx[k] = bodies[i].x[k] - bodies[j].x[k];
             }
-            let distance = ((dx[0] * dx[0]) + (dx[1] * dx[1]) + (dx[2] * dx[2])).sqrt();
-            e -= (bodies[i].mass * bodies[j].mass) / distance;
+            let distance =
+                ((dx[0] * dx[0]) + (dx[1] * dx[1]) + (dx[2] * dx[2]) + (softening * softening)).sqrt();
+            e.add_potential(-(bodies[i].mass * bodies[j].mass) / distance);
         }
     }
-
-    e
 }
 
-// Declaring an array of f64, starting at memory address, being aligned by 16
-//
-// This is corresponding to the anonymous type-declaration being used in `gcc #4`
-// ```
-// static __attribute__((aligned(16))) double mag[1000];
-// ```
-// The annotation `repr(C)` prevents the Rust-compiler from any
-// re-ordering of members.
-#[repr(C)]
-#[repr(align(16))]
-#[derive(Clone, Copy)]
-struct AlignedF64Array([f64; 1000]);
-
 // Representing the arrays `r` and `mag`, being re-used every iteration
 //
 // This is corresponding to the following code in `gcc #4`
@@ -198,33 +254,198 @@ struct AlignedF64Array([f64; 1000]);
 // } r[1000];
 // static __attribute__((aligned(16))) double mag[1000];
 // ```
+// Unlike in `gcc #4` the buffers are not limited to 1000 interactions, they
+// grow with the number of bodies passed to `advance`. As a `Vec<f64>` is only
+// guaranteed to be aligned by 8, `mag` is written by unaligned stores.
 pub struct BodiesAdvance {
-    r: [Delta; 1000],
-    mag: AlignedF64Array,
+    r: Vec<Delta>,
+    mag: Vec<f64>,
+    kernel: Kernel,
+    precision: Precision,
+    softening: f64,
+}
+
+// Kernel calculating the magnitudes `mag` from the deltas `r`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    // Portable code, one interaction at a time
+    Scalar,
+    // SSE2 intrinsics as in `gcc #4`, two interactions at a time; x86_64 only
+    Sse2,
+    // The `Sse2` kernel on AVX2 registers, four interactions at a time;
+    // x86_64 only
+    Avx2,
+}
+
+impl Kernel {
+    // The fastest kernel supported by the running CPU
+    pub fn detect() -> Kernel {
+        if Kernel::Avx2.is_supported() {
+            Kernel::Avx2
+        } else if Kernel::Sse2.is_supported() {
+            Kernel::Sse2
+        } else {
+            Kernel::Scalar
+        }
+    }
+
+    // Whether the running CPU supports the kernel
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Sse2 => is_x86_feature_detected!("sse2"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            Kernel::Sse2 | Kernel::Avx2 => false,
+        }
+    }
+}
+
+// Names of the kernels in the settings of a `Simulation`
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Kernel::Scalar => "scalar",
+            Kernel::Sse2 => "sse2",
+            Kernel::Avx2 => "avx2",
+        })
+    }
+}
+
+impl FromStr for Kernel {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Kernel, ()> {
+        match name {
+            "scalar" => Ok(Kernel::Scalar),
+            "sse2" => Ok(Kernel::Sse2),
+            "avx2" => Ok(Kernel::Avx2),
+            _ => Err(()),
+        }
+    }
+}
+
+// Precision of the inverse square root in the magnitudes `mag`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    // As in `gcc #4`, an inverse square root approximated on 32-bit floats,
+    // refined by the given number of Newton-Raphson iterations
+    Approximate { iterations: u32 },
+    // A square root and a division on 64-bit floats, as in `biffle`
+    Exact,
+}
+
+// The precision of `gcc #4`
+impl Default for Precision {
+    fn default() -> Precision {
+        Precision::Approximate { iterations: 2 }
+    }
+}
+
+// Names of the precisions in the settings of a `Simulation`, `exact` or
+// `approximate:` followed by the number of iterations
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Precision::Approximate { iterations } => write!(f, "approximate:{}", iterations),
+            Precision::Exact => f.write_str("exact"),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Precision, ()> {
+        match name.split_once(':') {
+            None if name == "exact" => Ok(Precision::Exact),
+            Some(("approximate", iterations)) => {
+                let iterations = iterations.parse().map_err(|_| ())?;
+                Ok(Precision::Approximate { iterations })
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for BodiesAdvance {
+    fn default() -> BodiesAdvance {
+        BodiesAdvance::new()
+    }
 }
 
 // Implementation of instanciating the buffers and function advance()
 impl BodiesAdvance {
-    // Constructor, instanciating the buffers `r` and `mag`
+    // Constructor, instanciating the empty buffers `r` and `mag`, using the
+    // fastest kernel supported by the CPU
     pub fn new() -> BodiesAdvance {
+        BodiesAdvance::with_kernel(Kernel::detect())
+    }
+
+    // Constructor using the given kernel, panics if the CPU does not support it
+    pub fn with_kernel(kernel: Kernel) -> BodiesAdvance {
+        assert!(kernel.is_supported(), "{:?} kernel is not supported by this CPU", kernel);
         BodiesAdvance {
-            r: [Delta::default(); 1000],
-            mag: AlignedF64Array([0.0; 1000]),
+            r: Vec::new(),
+            mag: Vec::new(),
+            kernel,
+            precision: Precision::default(),
+            softening: 0.0,
         }
     }
 
+    // Using the given precision instead of the one of `gcc #4`
+    pub fn with_precision(mut self, precision: Precision) -> BodiesAdvance {
+        self.precision = precision;
+        self
+    }
+
+    // Softening gravitation with the Plummer length `softening`, adding its
+    // square to every `dsquared`; `gcc #4` has none
+    pub fn with_softening(mut self, softening: f64) -> BodiesAdvance {
+        self.softening = softening;
+        self
+    }
+
+    // The kernel used by `advance`
+    pub fn kernel(&self) -> Kernel {
+        self.kernel
+    }
+
+    // The precision used by `advance`
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    // The softening length used by `advance`
+    pub fn softening(&self) -> f64 {
+        self.softening
+    }
+
     // Calculating advance of bodies within time dt, using the buffers `r` and `mag`
     #[inline]
-    pub fn advance(&mut self, bodies: &mut [Body; N_BODIES], dt: f64) {
+    pub fn advance(&mut self, bodies: &mut [Body], dt: f64) {
+        self.kick(bodies, dt);
+        drift(bodies, dt);
+    }
+
+    // Calculating the velocity change of bodies within time dt, using the
+    // buffers `r` and `mag`
+    #[inline]
+    pub fn kick(&mut self, bodies: &mut [Body], dt: f64) {
+        if bodies.is_empty() {
+            return;
+        }
+
         #[allow(non_snake_case)]
         let N = ((bodies.len() - 1) * bodies.len()) / 2;
 
-        // In `gcc #4` corresponding to local variable declaration dx
-        // inititalizing 2x64byte floats with zeros
-        // ```
-        //  __m128d dx[3];
-        // ```
-        let mut dx: [__m128d; 3] = unsafe { [_mm_setzero_pd(); 3] };
+        if self.r.len() < N {
+            self.r.resize(N, Delta::default());
+            self.mag.resize(N, 0.0);
+        }
 
         let mut k = 0;
         for i in 0..(bodies.len() - 1) {
@@ -236,83 +457,379 @@ impl BodiesAdvance {
             }
         }
 
-        // enumerate in +2 steps
-        for i_2 in 0..(N / 2) {
+        // Without softening, the kernels as in `gcc #4`, leaving out the
+        // addition of its square
+        match (self.kernel, self.softening != 0.0) {
+            (Kernel::Scalar, false) => self.magnitudes_scalar::<false>(N, dt),
+            (Kernel::Scalar, true) => self.magnitudes_scalar::<true>(N, dt),
+            // The kernel is checked to be supported on construction
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Sse2, false) => unsafe { self.magnitudes_sse2::<false>(N, dt) },
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Sse2, true) => unsafe { self.magnitudes_sse2::<true>(N, dt) },
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Avx2, false) => unsafe { self.magnitudes_avx2::<false>(N, dt) },
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Avx2, true) => unsafe { self.magnitudes_avx2::<true>(N, dt) },
+            #[cfg(not(target_arch = "x86_64"))]
+            (Kernel::Sse2, _) | (Kernel::Avx2, _) => unreachable!(),
+        }
+
+        let mut k = 0;
+        for i in 0..(bodies.len() - 1) {
+            for j in (i + 1)..bodies.len() {
+                for m in 0..3 {
+                    bodies[i].v[m] -= (self.r[k].dx[m] * bodies[j].mass) * self.mag[k];
+
+                    bodies[j].v[m] += (self.r[k].dx[m] * bodies[i].mass) * self.mag[k];
+                }
+                k += 1;
+            }
+        }
+    }
+
+    // Calculating `mag` from `r` one interaction at a time, adding the squared
+    // softening length if `SOFTENED`
+    #[allow(non_snake_case)]
+    fn magnitudes_scalar<const SOFTENED: bool>(&mut self, N: usize, dt: f64) {
+        for (mag, r) in self.mag[..N].iter_mut().zip(&self.r[..N]) {
+            let mut dsquared = (r.dx[0] * r.dx[0]) + (r.dx[1] * r.dx[1]) + (r.dx[2] * r.dx[2]);
+            if SOFTENED {
+                dsquared += self.softening * self.softening;
+            }
+
+            *mag = match self.precision {
+                Precision::Exact => dt / (dsquared * dsquared.sqrt()),
+                Precision::Approximate { iterations } => {
+                    let mut distance = rsqrt_approximate(dsquared);
+                    for _ in 0..iterations {
+                        distance = distance * 1.5 - ((0.5 * dsquared) * distance) * (distance * distance);
+                    }
+                    dt / dsquared * distance
+                }
+            };
+        }
+    }
+
+    // Calculating `mag` from `r` two interactions at a time, using SSE2
+    //
+    // With an odd number of interactions, the last one is left over by
+    // `gcc #4` (relying on 10 interactions), here it is padded with a unit
+    // delta to a full vector as in `magnitudes_avx2`, so that every magnitude
+    // is computed by the same operations.
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse2")]
+    #[allow(non_snake_case)]
+    unsafe fn magnitudes_sse2<const SOFTENED: bool>(&mut self, N: usize, dt: f64) {
+        // In `gcc #4` corresponding to local variable declaration dx
+        // inititalizing 2x64-bit floats with zeros
+        // ```
+        //  __m128d dx[3];
+        // ```
+        let mut dx: [__m128d; 3] = [_mm_setzero_pd(); 3];
+        let unit = Delta { dx: [1.0, 0.0, 0.0], fill: 0.0 };
+
+        // enumerate in +2 steps, the last one possibly padded
+        for i_2 in 0..N.div_ceil(2) {
             let i = i_2 * 2;
+            let second = if i + 1 < N { &self.r[i + 1] } else { &unit };
 
             for m in 0..3 {
                 dx[m] = unsafe { _mm_loadl_pd(dx[m], &self.r[i].dx[m]) };
-                dx[m] = unsafe { _mm_loadh_pd(dx[m], &self.r[i + 1].dx[m]) };
+                dx[m] = unsafe { _mm_loadh_pd(dx[m], &second.dx[m]) };
             }
 
             // In `gcc #4` corresponding to implicit call of _m128f operations
             // ```
             //  dsquared = dx[0] * dx[0] + dx[1] * dx[1] + dx[2] * dx[2];
             // ```
-            let dsquared: __m128d = unsafe {
-                _mm_add_pd(
-                    _mm_add_pd(_mm_mul_pd(dx[0], dx[0]), _mm_mul_pd(dx[1], dx[1])),
-                    _mm_mul_pd(dx[2], dx[2]),
-                )
+            let dsquared: __m128d = _mm_add_pd(
+                _mm_add_pd(_mm_mul_pd(dx[0], dx[0]), _mm_mul_pd(dx[1], dx[1])),
+                _mm_mul_pd(dx[2], dx[2]),
+            );
+            // Not in `gcc #4`, adding the squared softening length if `SOFTENED`
+            let dsquared = if SOFTENED {
+                _mm_add_pd(dsquared, _mm_set1_pd(self.softening * self.softening))
+            } else {
+                dsquared
             };
+            let dmag: __m128d = match self.precision {
+                Precision::Exact => {
+                    _mm_div_pd(_mm_set1_pd(dt), _mm_mul_pd(dsquared, _mm_sqrt_pd(dsquared)))
+                }
+                Precision::Approximate { iterations } => {
+                    // In `gcc #4` corresponding to call of _m128f operations
+                    // ```
+                    //  distance = _mm_cvtps_pd(_mm_rsqrt_ps(_mm_cvtpd_ps(dsquared)))
+                    // ```
+                    let mut distance = _mm_cvtps_pd(_mm_rsqrt_ps(_mm_cvtpd_ps(dsquared)));
+
+                    // repeat 2 times in `gcc #4`
+                    for _ in 0..iterations {
+                        // In `gcc #4` corresponding to implicit call of _m128f operations
+                        // ```
+                        // distance = distance * _mm_set1_pd(1.5)
+                        //            - ((_mm_set1_pd(0.5) * dsquared) * distance)
+                        //            * (distance * distance);
+                        // ```
+                        distance = _mm_sub_pd(
+                            _mm_mul_pd(distance, _mm_set1_pd(1.5)),
+                            _mm_mul_pd(
+                                _mm_mul_pd(_mm_mul_pd(_mm_set1_pd(0.5), dsquared), distance),
+                                _mm_mul_pd(distance, distance),
+                            ),
+                        );
+                    }
+
+                    // In `gcc #4` corresponding to implicit call of _m128f operations
+                    // ```
+                    // dmag = _mm_set1_pd(dt) / (dsquared) * distance;
+                    // ```
+                    _mm_mul_pd(_mm_div_pd(_mm_set1_pd(dt), dsquared), distance)
+                }
+            };
+
             // In `gcc #4` corresponding to call of _m128f operations
             // ```
-            //  distance = _mm_cvtps_pd(_mm_rsqrt_ps(_mm_cvtpd_ps(dsquared)))
+            // _mm_store_pd(&mag[i], dmag);
             // ```
-            let mut distance = unsafe { _mm_cvtps_pd(_mm_rsqrt_ps(_mm_cvtpd_ps(dsquared))) };
-
-            // repeat 2 times
-            for _ in 0..2 {
-                // In `gcc #4` corresponding to implicit call of _m128f operations
-                // ```
-                // distance = distance * _mm_set1_pd(1.5)
-                //            - ((_mm_set1_pd(0.5) * dsquared) * distance)
-                //            * (distance * distance);
-                // ```
-                distance = unsafe {
-                    _mm_sub_pd(
-                        _mm_mul_pd(distance, _mm_set1_pd(1.5)),
-                        _mm_mul_pd(
-                            _mm_mul_pd(_mm_mul_pd(_mm_set1_pd(0.5), dsquared), distance),
-                            _mm_mul_pd(distance, distance),
-                        ),
-                    )
-                };
+            unsafe {
+                if i + 1 < N {
+                    _mm_storeu_pd(&mut self.mag[i], dmag);
+                } else {
+                    _mm_storel_pd(&mut self.mag[i], dmag);
+                }
             }
+        }
+    }
 
-            // In `gcc #4` corresponding to implicit call of _m128f operations
-            // ```
-            // dmag = _mm_set1_pd(dt) / (dsquared) * distance;
-            // ```
-            let dmag: __m128d =
-                unsafe { _mm_mul_pd(_mm_div_pd(_mm_set1_pd(dt), dsquared), distance) };
+    // Calculating `mag` from `r` four interactions at a time, using AVX2
+    //
+    // The interactions left over are padded with unit deltas to a full
+    // vector, so that every magnitude is computed by the same operations.
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    #[allow(non_snake_case)]
+    unsafe fn magnitudes_avx2<const SOFTENED: bool>(&mut self, N: usize, dt: f64) {
+        // enumerate in +4 steps
+        for i_4 in 0..(N / 4) {
+            let i = i_4 * 4;
+            let r = &self.r;
+            let dmag = magnitudes_avx2_x4::<SOFTENED>([&r[i], &r[i + 1], &r[i + 2], &r[i + 3]], dt, self.precision, self.softening);
+            unsafe {
+                _mm256_storeu_pd(&mut self.mag[i], dmag);
+            }
+        }
 
-            // In `gcc #4` corresponding to call of _m128f operations
-            // ```
-            // _mm_store_pd(&mag[i], dmag);
-            // ```
+        let rest = N % 4;
+        if rest > 0 {
+            let i = N - rest;
+            let unit = Delta { dx: [1.0, 0.0, 0.0], fill: 0.0 };
+            let lane = |l: usize| if l < rest { &self.r[i + l] } else { &unit };
+
+            let mut mag = [0.0; 4];
+            let dmag = magnitudes_avx2_x4::<SOFTENED>([lane(0), lane(1), lane(2), lane(3)], dt, self.precision, self.softening);
             unsafe {
-                _mm_store_pd(&mut (self.mag.0)[i], dmag);
+                _mm256_storeu_pd(mag.as_mut_ptr(), dmag);
             }
+            self.mag[i..N].copy_from_slice(&mag[..rest]);
         }
+    }
+}
 
-        let mut k = 0;
-        for i in 0..(bodies.len() - 1) {
-            for j in (i + 1)..bodies.len() {
-                for m in 0..3 {
-                    bodies[i].v[m] -= (self.r[k].dx[m] * bodies[j].mass) * (self.mag.0)[k];
+// Approximating the inverse square root on a 32-bit float, seeding the Newton
+// steps of the scalar kernel
+//
+// On x86_64 this is the `_mm_rsqrt_ps` of the SIMD kernels on a single lane,
+// so that all kernels compute the same magnitudes to the last bit. Elsewhere
+// the seed is the rounded inverse square root, and the approximate precision
+// matches the one on x86_64 only within its own error.
+#[inline]
+fn rsqrt_approximate(dsquared: f64) -> f64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // SSE is part of every x86_64 CPU
+        unsafe { _mm_cvtss_f32(_mm_rsqrt_ss(_mm_set_ss(dsquared as f32))) as f64 }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        (dsquared as f32).sqrt().recip() as f64
+    }
+}
 
-                    bodies[j].v[m] += (self.r[k].dx[m] * bodies[i].mass) * (self.mag.0)[k];
-                }
-                k += 1;
+// Calculating the magnitudes of four deltas, the operations being the ones of
+// `magnitudes_sse2` on 4x64-bit floats
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+fn magnitudes_avx2_x4<const SOFTENED: bool>(r: [&Delta; 4], dt: f64, precision: Precision, softening: f64) -> __m256d {
+    let mut dx: [__m256d; 3] = [_mm256_setzero_pd(); 3];
+    for m in 0..3 {
+        dx[m] = _mm256_set_pd(r[3].dx[m], r[2].dx[m], r[1].dx[m], r[0].dx[m]);
+    }
+
+    let dsquared: __m256d = _mm256_add_pd(
+        _mm256_add_pd(_mm256_mul_pd(dx[0], dx[0]), _mm256_mul_pd(dx[1], dx[1])),
+        _mm256_mul_pd(dx[2], dx[2]),
+    );
+    let dsquared = if SOFTENED {
+        _mm256_add_pd(dsquared, _mm256_set1_pd(softening * softening))
+    } else {
+        dsquared
+    };
+
+    match precision {
+        Precision::Exact => {
+            _mm256_div_pd(_mm256_set1_pd(dt), _mm256_mul_pd(dsquared, _mm256_sqrt_pd(dsquared)))
+        }
+        Precision::Approximate { iterations } => {
+            // The inverse square root is approximated on 4x32-bit floats, as in
+            // `magnitudes_sse2`
+            let mut distance = _mm256_cvtps_pd(_mm_rsqrt_ps(_mm256_cvtpd_ps(dsquared)));
+
+            for _ in 0..iterations {
+                distance = _mm256_sub_pd(
+                    _mm256_mul_pd(distance, _mm256_set1_pd(1.5)),
+                    _mm256_mul_pd(
+                        _mm256_mul_pd(_mm256_mul_pd(_mm256_set1_pd(0.5), dsquared), distance),
+                        _mm256_mul_pd(distance, distance),
+                    ),
+                );
             }
+
+            _mm256_mul_pd(_mm256_div_pd(_mm256_set1_pd(dt), dsquared), distance)
         }
+    }
+}
 
-        for i in 0..bodies.len() {
-            for m in 0..3 {
-                bodies[i].x[m] += dt * bodies[i].v[m];
+// Calculating the position change of bodies within time dt
+#[inline]
+pub fn drift(bodies: &mut [Body], dt: f64) {
+    for i in 0..bodies.len() {
+        for m in 0..3 {
+            bodies[i].x[m] += dt * bodies[i].v[m];
+        }
+    }
+}
+
+// Bodies and buffers of a running simulation, behind the common
+// `NBodySimulator` interface
+pub struct Simulation {
+    bodies: Vec<Body>,
+    sim: BodiesAdvance,
+}
+
+impl Simulation {
+    // Creates a simulation using the given kernel, panics if the CPU does not
+    // support it
+    pub fn with_kernel(bodies: Vec<Body>, kernel: Kernel) -> Simulation {
+        Simulation {
+            bodies,
+            sim: BodiesAdvance::with_kernel(kernel),
+        }
+    }
+
+    // Using the given precision instead of the one of `gcc #4`
+    pub fn with_precision(mut self, precision: Precision) -> Simulation {
+        self.sim = self.sim.with_precision(precision);
+        self
+    }
+
+    // Softening gravitation with the Plummer length `softening`, 0 by default
+    pub fn with_softening(mut self, softening: f64) -> Simulation {
+        self.sim = self.sim.with_softening(softening);
+        self
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "rehnberger";
+
+    type Bodies = Vec<Body>;
+
+    fn new(bodies: Self::Bodies) -> Simulation {
+        Simulation {
+            bodies,
+            sim: BodiesAdvance::new(),
+        }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::new(STARTING_STATE.to_vec())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(bodies.iter().map(Body::from).collect())
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        self.bodies.iter().map(BodyState::from).collect()
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        self.bodies = bodies.iter().map(Body::from).collect();
+    }
+
+    // The kernel, precision and softening, the softening in exponent notation
+    // which reads back to the same bits
+    fn settings(&self) -> String {
+        format!(
+            "kernel={} precision={} softening={:e}",
+            self.sim.kernel(),
+            self.sim.precision(),
+            self.sim.softening()
+        )
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        for (key, value) in parse_settings(settings)? {
+            match key {
+                "kernel" => {
+                    let kernel: Kernel = parse_setting(key, value)?;
+                    if !kernel.is_supported() {
+                        return Err(format!("{:?} kernel is not supported by this CPU", kernel));
+                    }
+                    self.sim.kernel = kernel;
+                }
+                "precision" => self.sim.precision = parse_setting(key, value)?,
+                "softening" => self.sim.softening = parse_setting(key, value)?,
+                _ => return Err(unknown_setting(key)),
             }
         }
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.sim.advance(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.sim.kick(&mut self.bodies, dt);
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, dt);
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.sim.softening())
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.sim.softening())
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.x).collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter().map(|body| body.v).collect()
     }
 }
 
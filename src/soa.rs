@@ -0,0 +1,346 @@
+//! n-body simulation in Rust - structure-of-arrays version
+//!
+//! Changes from `biffle`:
+//! 1. Keep each coordinate of positions and velocities, and the masses, in
+//!    separate contiguous arrays instead of an array of bodies.
+//! 2. Process the interactions of one body at a time, in loops over
+//!    consecutive elements that the compiler can vectorize without gathering.
+//!
+//! The arithmetic is done in the same order as in `biffle`, so both produce
+//! the same results.
+
+use std::f64::consts::PI;
+
+use crate::checkpoint::{parse_softening, softening_settings};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
+pub const SOLAR_MASS: f64 = 4. * PI * PI;
+
+/// State of every body in the system, one array per component.
+#[derive(Clone, Debug, Default)]
+pub struct Bodies {
+    x: Vec<f64>,
+    y: Vec<f64>,
+    z: Vec<f64>,
+    vx: Vec<f64>,
+    vy: Vec<f64>,
+    vz: Vec<f64>,
+    mass: Vec<f64>,
+}
+
+impl Bodies {
+    /// Number of bodies.
+    pub fn len(&self) -> usize {
+        self.mass.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mass.is_empty()
+    }
+}
+
+impl From<&[BodyState]> for Bodies {
+    fn from(states: &[BodyState]) -> Bodies {
+        let mut bodies = Bodies::default();
+        for state in states {
+            bodies.x.push(state.position[0]);
+            bodies.y.push(state.position[1]);
+            bodies.z.push(state.position[2]);
+            bodies.vx.push(state.velocity[0]);
+            bodies.vy.push(state.velocity[1]);
+            bodies.vz.push(state.velocity[2]);
+            bodies.mass.push(state.mass);
+        }
+        bodies
+    }
+}
+
+impl From<&Bodies> for Vec<BodyState> {
+    fn from(bodies: &Bodies) -> Vec<BodyState> {
+        (0..bodies.len())
+            .map(|i| BodyState {
+                name: String::new(),
+                position: [bodies.x[i], bodies.y[i], bodies.z[i]],
+                velocity: [bodies.vx[i], bodies.vy[i], bodies.vz[i]],
+                mass: bodies.mass[i],
+            })
+            .collect()
+    }
+}
+
+/// Per-body buffers for the interactions of one body with the following ones,
+/// and the Plummer softening length added to every distance.
+///
+/// Kicks resize the buffers to the number of bodies; new ones leave
+/// gravitation unsoftened.
+#[derive(Clone, Debug, Default)]
+pub struct Scratch {
+    dx: Vec<f64>,
+    dy: Vec<f64>,
+    dz: Vec<f64>,
+    mag: Vec<f64>,
+    softening: f64,
+}
+
+impl Scratch {
+    /// Buffers sized for `len` bodies.
+    pub fn new(len: usize) -> Scratch {
+        Scratch {
+            dx: vec![0.; len],
+            dy: vec![0.; len],
+            dz: vec![0.; len],
+            mag: vec![0.; len],
+            softening: 0.,
+        }
+    }
+}
+
+/// Steps the simulation forward by `steps` time-steps of length `dt`, with
+/// the buffers of `scratch`.
+pub fn advance(bodies: &mut Bodies, steps: usize, dt: f64, scratch: &mut Scratch) {
+    for _ in 0..steps {
+        scratch.kick(bodies, dt);
+        drift(bodies, dt);
+    }
+}
+
+/// Applies the gravitation between every pair of bodies to their velocities
+/// over a time-step `dt`, with the buffers of `scratch`.
+pub fn kick(bodies: &mut Bodies, dt: f64, scratch: &mut Scratch) {
+    scratch.kick(bodies, dt);
+}
+
+/// Moves each body along its velocity over a time-step `dt`.
+pub fn drift(bodies: &mut Bodies, dt: f64) {
+    let Bodies { x, y, z, vx, vy, vz, .. } = bodies;
+    for (x, vx) in x.iter_mut().zip(vx.iter()) {
+        *x += dt * vx;
+    }
+    for (y, vy) in y.iter_mut().zip(vy.iter()) {
+        *y += dt * vy;
+    }
+    for (z, vz) in z.iter_mut().zip(vz.iter()) {
+        *z += dt * vz;
+    }
+}
+
+impl Scratch {
+    fn kick(&mut self, bodies: &mut Bodies, dt: f64) {
+        // Leave out the softening when there is none.
+        if self.softening != 0. {
+            self.kick_with::<true>(bodies, dt);
+        } else {
+            self.kick_with::<false>(bodies, dt);
+        }
+    }
+
+    /// Kick with the distances softened if `SOFTENED`.
+    fn kick_with<const SOFTENED: bool>(&mut self, bodies: &mut Bodies, dt: f64) {
+        let n = bodies.len();
+        for buffer in [&mut self.dx, &mut self.dy, &mut self.dz, &mut self.mag].iter_mut() {
+            buffer.resize(n, 0.);
+        }
+        let Bodies { x, y, z, vx, vy, vz, mass } = bodies;
+        let softening_squared = self.softening * self.softening;
+        let scratch = self;
+
+        for i in 0..n {
+            let (xi, yi, zi, mi) = (x[i], y[i], z[i], mass[i]);
+            let dx = &mut scratch.dx[i + 1..n];
+            let dy = &mut scratch.dy[i + 1..n];
+            let dz = &mut scratch.dz[i + 1..n];
+            let mag = &mut scratch.mag[i + 1..n];
+
+            // Compute the vectors to, and the `1/d^3` magnitudes of, the following bodies.
+            for j in 0..n - i - 1 {
+                dx[j] = xi - x[i + 1 + j];
+                dy[j] = yi - y[i + 1 + j];
+                dz[j] = zi - z[i + 1 + j];
+                let mut distance_squared = dx[j] * dx[j] + dy[j] * dy[j] + dz[j] * dz[j];
+                if SOFTENED {
+                    distance_squared += softening_squared;
+                }
+                mag[j] = dt / (distance_squared * distance_squared.sqrt());
+            }
+
+            // Apply this body's gravitation to the following bodies.
+            let (vx_j, vy_j, vz_j) = (&mut vx[i + 1..n], &mut vy[i + 1..n], &mut vz[i + 1..n]);
+            for j in 0..n - i - 1 {
+                let i_mass_mag = mi * mag[j];
+                vx_j[j] += dx[j] * i_mass_mag;
+                vy_j[j] += dy[j] * i_mass_mag;
+                vz_j[j] += dz[j] * i_mass_mag;
+            }
+
+            // Apply the following bodies' gravitation to this body.
+            let mass_j = &mass[i + 1..n];
+            let (mut vxi, mut vyi, mut vzi) = (vx[i], vy[i], vz[i]);
+            for j in 0..n - i - 1 {
+                let j_mass_mag = mass_j[j] * mag[j];
+                vxi -= dx[j] * j_mass_mag;
+                vyi -= dy[j] * j_mass_mag;
+                vzi -= dz[j] * j_mass_mag;
+            }
+            vx[i] = vxi;
+            vy[i] = vyi;
+            vz[i] = vzi;
+        }
+    }
+}
+
+/// Adjust the Sun's velocity to offset system momentum.
+pub fn offset_momentum(bodies: &mut Bodies) {
+    if bodies.is_empty() {
+        return;
+    }
+    let (mut vx, mut vy, mut vz) = (0., 0., 0.);
+    for i in 1..bodies.len() {
+        vx -= bodies.vx[i] * bodies.mass[i] / SOLAR_MASS;
+        vy -= bodies.vy[i] * bodies.mass[i] / SOLAR_MASS;
+        vz -= bodies.vz[i] * bodies.mass[i] / SOLAR_MASS;
+    }
+    bodies.vx[0] = vx;
+    bodies.vy[0] = vy;
+    bodies.vz[0] = vz;
+}
+
+/// Compute the system energy.
+pub fn compute_energy(bodies: &Bodies) -> f64 {
+    softened_energy(bodies, 0.)
+}
+
+/// Compute the system energy with the potential softened by `softening`.
+pub fn softened_energy(bodies: &Bodies, softening: f64) -> f64 {
+    let mut energy = 0.;
+    add_energy(bodies, softening, &mut energy);
+    energy
+}
+
+/// Compute the system energy, split into kinetic and potential parts.
+pub fn energy_breakdown(bodies: &Bodies) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.)
+}
+
+/// Compute the parts of the system energy, with the potential softened by
+/// `softening`.
+pub fn softened_energy_breakdown(bodies: &Bodies, softening: f64) -> EnergyBreakdown {
+    let mut breakdown = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut breakdown);
+    breakdown
+}
+
+fn add_energy<E: EnergyTerms>(bodies: &Bodies, softening: f64, energy: &mut E) {
+    let n = bodies.len();
+    for i in 0..n {
+        // Add the kinetic energy for each body.
+        energy.add_kinetic(
+            0.5 * bodies.mass[i]
+                * (bodies.vx[i] * bodies.vx[i] + bodies.vy[i] * bodies.vy[i] + bodies.vz[i] * bodies.vz[i]),
+        );
+
+        // Add the potential energy between this body and every other body.
+        for j in i + 1..n {
+            let dx = bodies.x[i] - bodies.x[j];
+            let dy = bodies.y[i] - bodies.y[j];
+            let dz = bodies.z[i] - bodies.z[j];
+            let distance_squared = dx * dx + dy * dy + dz * dz + softening * softening;
+            energy.add_potential(-bodies.mass[i] * bodies.mass[j] / f64::sqrt(distance_squared));
+        }
+    }
+}
+
+/// Bodies and buffers of a running simulation, behind the common
+/// `NBodySimulator` interface.
+pub struct Simulation {
+    bodies: Bodies,
+    scratch: Scratch,
+}
+
+impl Simulation {
+    /// Softens gravitation with a Plummer length `softening`, 0 by default.
+    pub fn with_softening(mut self, softening: f64) -> Simulation {
+        self.scratch.softening = softening;
+        self
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.scratch.softening
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "soa";
+
+    type Bodies = Bodies;
+
+    fn new(bodies: Bodies) -> Simulation {
+        let scratch = Scratch::new(bodies.len());
+        Simulation { bodies, scratch }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::from_bodies(&crate::body::starting_state())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(Bodies::from(bodies))
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        Vec::from(&self.bodies)
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        let softening = self.softening();
+        *self = Simulation::from_bodies(bodies).with_softening(softening);
+    }
+
+    fn settings(&self) -> String {
+        softening_settings(self.softening())
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        self.scratch.softening = parse_softening(settings)?;
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0..steps {
+            self.scratch.kick(&mut self.bodies, dt);
+            drift(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.scratch.kick(&mut self.bodies, dt);
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, dt);
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.softening())
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.softening())
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        (0..self.bodies.len())
+            .map(|i| [self.bodies.x[i], self.bodies.y[i], self.bodies.z[i]])
+            .collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        (0..self.bodies.len())
+            .map(|i| [self.bodies.vx[i], self.bodies.vy[i], self.bodies.vz[i]])
+            .collect()
+    }
+}
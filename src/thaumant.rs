@@ -6,6 +6,10 @@ use std::f64::consts::PI;
 use std::ops::{Add, Sub, Mul, AddAssign, SubAssign};
 use std::default::Default;
 
+use crate::checkpoint::{parse_softening, softening_settings};
+use crate::diagnostics::{EnergyBreakdown, EnergyTerms};
+use crate::{BodyState, NBodySimulator};
+
 #[derive(Clone, Debug)]
 pub struct Vec3D(f64, f64, f64);
 
@@ -16,9 +20,18 @@ impl Vec3D {
             + self.2 * self.2
     }
 
-    pub fn magnitude(&self) -> f64 {
+    /// `dt / |v|^3`, the scale of the velocity change over `dt` between two
+    /// bodies `v` apart.
+    pub fn magnitude(&self, dt: f64) -> f64 {
         let sum = self.sum_squares();
-        DT / (sum * sum.sqrt())
+        dt / (sum * sum.sqrt())
+    }
+
+    /// `dt / (|v|^2 + ε^2)^(3/2)`, the same scale under Plummer softening of
+    /// length `softening` ε, which keeps it finite for coincident bodies.
+    pub fn softened_magnitude(&self, dt: f64, softening: f64) -> f64 {
+        let sum = self.sum_squares() + softening * softening;
+        dt / (sum * sum.sqrt())
     }
 }
 
@@ -84,15 +97,35 @@ pub struct Body {
     mass: f64,
 }
 
-pub const DT: f64 = 0.01;
+impl From<&BodyState> for Body {
+    fn from(state: &BodyState) -> Body {
+        let [x, y, z] = state.position;
+        let [vx, vy, vz] = state.velocity;
+        Body {
+            position: Vec3D(x, y, z),
+            velocity: Vec3D(vx, vy, vz),
+            mass: state.mass,
+        }
+    }
+}
 
+impl From<&Body> for BodyState {
+    fn from(body: &Body) -> BodyState {
+        BodyState {
+            name: String::new(),
+            position: [body.position.0, body.position.1, body.position.2],
+            velocity: [body.velocity.0, body.velocity.1, body.velocity.2],
+            mass: body.mass,
+        }
+    }
+}
+
+/// Number of bodies in the starting state.
 pub const BODIES_COUNT: usize = 5;
 
 pub const SOLAR_MASS: f64 = 4. * PI * PI;
 pub const DAYS_PER_YEAR: f64 = 365.24;
 
-pub const INTERACTIONS: usize = BODIES_COUNT * (BODIES_COUNT - 1) / 2;
-
 pub const STARTING_STATE: [Body; BODIES_COUNT] = [
     // Sun
     Body {
@@ -158,49 +191,99 @@ pub const STARTING_STATE: [Body; BODIES_COUNT] = [
     },
 ];
 
-/// Steps the simulation forward by one time-step.
-pub fn advance(bodies: &mut [Body; BODIES_COUNT], steps: usize) {
-    let mut d_positions: [Vec3D; INTERACTIONS] = Default::default();
-    let mut magnitudes = [0.; INTERACTIONS];
-
+/// Steps the simulation forward by `steps` time-steps of length `dt`, with
+/// the buffers of `interactions`.
+pub fn advance(bodies: &mut [Body], steps: usize, dt: f64, interactions: &mut Interactions) {
     for _ in 0 .. steps {
+        interactions.kick(bodies, dt);
+        drift(bodies, dt);
+    }
+}
+
+/// Applies the gravitation between every pair of bodies to their velocities
+/// over a time-step `dt`, with the buffers of `interactions`.
+pub fn kick(bodies: &mut [Body], dt: f64, interactions: &mut Interactions) {
+    interactions.kick(bodies, dt);
+}
+
+/// Moves each body along its velocity over a time-step `dt`.
+pub fn drift(bodies: &mut [Body], dt: f64) {
+    for body in bodies.iter_mut() {
+        body.position += &body.velocity * dt;
+    }
+}
+
+/// Buffers for the vectors and magnitudes between each unique pair of bodies,
+/// reused across time-steps.
+///
+/// Kicks resize the buffers to the number of bodies; new ones leave
+/// gravitation unsoftened.
+#[derive(Clone, Debug, Default)]
+pub struct Interactions {
+    d_positions: Vec<Vec3D>,
+    magnitudes: Vec<f64>,
+    softening: f64,
+}
+
+impl Interactions {
+    /// Buffers sized for `bodies_count` bodies.
+    pub fn new(bodies_count: usize) -> Interactions {
+        let interactions = bodies_count * bodies_count.saturating_sub(1) / 2;
+        Interactions {
+            d_positions: vec![Vec3D::default(); interactions],
+            magnitudes: vec![0.; interactions],
+            softening: 0.,
+        }
+    }
+
+    fn kick(&mut self, bodies: &mut [Body], dt: f64) {
+        let interactions = bodies.len() * bodies.len().saturating_sub(1) / 2;
+        self.d_positions.resize(interactions, Vec3D::default());
+        self.magnitudes.resize(interactions, 0.);
+
         // Compute point-to-point vectors between each unique pair of bodies.
         let mut k = 0;
         for (i, body1) in bodies.iter().enumerate() {
             for body2 in &bodies[i + 1 ..] {
-                d_positions[k] = &body1.position - &body2.position;
+                self.d_positions[k] = &body1.position - &body2.position;
                 k += 1;
             }
         }
-    
-        // Compute the magnitude between each pair of bodies.
-        for (magnitude, d_position) in magnitudes.iter_mut().zip(d_positions.iter()) {
-            *magnitude = d_position.magnitude();
-        };
-    
+
+        // Compute the magnitude between each pair of bodies, leaving out the
+        // softening when there is none.
+        let magnitudes = self.magnitudes.iter_mut().zip(self.d_positions.iter());
+        if self.softening != 0. {
+            for (magnitude, d_position) in magnitudes {
+                *magnitude = d_position.softened_magnitude(dt, self.softening);
+            }
+        } else {
+            for (magnitude, d_position) in magnitudes {
+                *magnitude = d_position.magnitude(dt);
+            }
+        }
+
         // Apply every other body's gravitation to each body's velocity.
         let mut k = 0;
-        for i in 0 .. BODIES_COUNT - 1 {
+        for i in 0 .. bodies.len() {
             let (body1, rest) = bodies[i..].split_first_mut().unwrap();
             for body2 in rest {
-                let d_position  = &d_positions[k];
-                let magnitude   = magnitudes[k];
+                let d_position  = &self.d_positions[k];
+                let magnitude   = self.magnitudes[k];
                 body1.velocity -= d_position * (body2.mass * magnitude);
                 body2.velocity += d_position * (body1.mass * magnitude);
                 k += 1;
             }
         }
-    
-        // Update positions
-        for body in bodies.iter_mut() {
-            body.position += &body.velocity * DT;
-        }
     }
 }
 
 /// Adjust the Sun's velocity to offset system momentum.
-pub fn offset_momentum(bodies: &mut [Body; BODIES_COUNT]) {
-    let (sun, planets) = bodies.split_first_mut().unwrap();
+pub fn offset_momentum(bodies: &mut [Body]) {
+    let (sun, planets) = match bodies.split_first_mut() {
+        Some(split) => split,
+        None => return,
+    };
     sun.velocity = Default::default();
     for planet in planets {
         sun.velocity -= &planet.velocity * (planet.mass / SOLAR_MASS);
@@ -208,18 +291,135 @@ pub fn offset_momentum(bodies: &mut [Body; BODIES_COUNT]) {
 }
 
 /// Print the system energy.
-pub fn compute_energy(bodies: &mut [Body; BODIES_COUNT]) -> f64 {
+pub fn compute_energy(bodies: &[Body]) -> f64 {
+    softened_energy(bodies, 0.)
+}
+
+/// Compute the system energy with the potential softened by `softening`.
+pub fn softened_energy(bodies: &[Body], softening: f64) -> f64 {
     let mut energy = 0.;
+    add_energy(bodies, softening, &mut energy);
+    energy
+}
+
+/// Compute the system energy, split into kinetic and potential parts.
+pub fn energy_breakdown(bodies: &[Body]) -> EnergyBreakdown {
+    softened_energy_breakdown(bodies, 0.)
+}
+
+/// Compute the parts of the system energy, with the potential softened by
+/// `softening`.
+pub fn softened_energy_breakdown(bodies: &[Body], softening: f64) -> EnergyBreakdown {
+    let mut breakdown = EnergyBreakdown::default();
+    add_energy(bodies, softening, &mut breakdown);
+    breakdown
+}
+
+fn add_energy<E: EnergyTerms>(bodies: &[Body], softening: f64, energy: &mut E) {
     for (i, body1) in bodies.iter().enumerate() {
         // Add the kinetic energy for each body.
-        energy += 0.5
+        energy.add_kinetic(0.5
             * body1.mass
-            * body1.velocity.sum_squares();
+            * body1.velocity.sum_squares());
         // Add the potential energy between this body and every other body.
         for body2 in &bodies[i + 1 ..] {
             let d_position = &body1.position - &body2.position;
-            energy -= body1.mass * body2.mass / d_position.sum_squares().sqrt();
+            let distance = (d_position.sum_squares() + softening * softening).sqrt();
+            energy.add_potential(-body1.mass * body2.mass / distance);
         }
     }
-    energy
+}
+
+/// Bodies of a running simulation, behind the common `NBodySimulator` interface.
+pub struct Simulation {
+    bodies: Vec<Body>,
+    interactions: Interactions,
+}
+
+impl Simulation {
+    /// Softens gravitation with a Plummer length `softening`, 0 by default.
+    pub fn with_softening(mut self, softening: f64) -> Simulation {
+        self.interactions.softening = softening;
+        self
+    }
+
+    pub fn softening(&self) -> f64 {
+        self.interactions.softening
+    }
+}
+
+impl NBodySimulator for Simulation {
+    const NAME: &'static str = "thaumant";
+
+    type Bodies = Vec<Body>;
+
+    fn new(bodies: Self::Bodies) -> Simulation {
+        let interactions = Interactions::new(bodies.len());
+        Simulation { bodies, interactions }
+    }
+
+    fn starting_state() -> Simulation {
+        Simulation::new(STARTING_STATE.to_vec())
+    }
+
+    fn from_bodies(bodies: &[BodyState]) -> Simulation {
+        Simulation::new(bodies.iter().map(Body::from).collect())
+    }
+
+    fn bodies(&self) -> Vec<BodyState> {
+        self.bodies.iter().map(BodyState::from).collect()
+    }
+
+    fn set_bodies(&mut self, bodies: &[BodyState]) {
+        let softening = self.softening();
+        *self = Simulation::from_bodies(bodies).with_softening(softening);
+    }
+
+    fn settings(&self) -> String {
+        softening_settings(self.softening())
+    }
+
+    fn apply_settings(&mut self, settings: &str) -> Result<(), String> {
+        self.interactions.softening = parse_softening(settings)?;
+        Ok(())
+    }
+
+    fn offset_momentum(&mut self) {
+        offset_momentum(&mut self.bodies);
+    }
+
+    fn advance(&mut self, steps: usize, dt: f64) {
+        for _ in 0 .. steps {
+            self.interactions.kick(&mut self.bodies, dt);
+            drift(&mut self.bodies, dt);
+        }
+    }
+
+    fn kick(&mut self, dt: f64) {
+        self.interactions.kick(&mut self.bodies, dt);
+    }
+
+    fn drift(&mut self, dt: f64) {
+        drift(&mut self.bodies, dt);
+    }
+
+    fn energy(&self) -> f64 {
+        softened_energy(&self.bodies, self.softening())
+    }
+
+    fn energy_breakdown(&self) -> EnergyBreakdown {
+        softened_energy_breakdown(&self.bodies, self.softening())
+    }
+
+    fn positions(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter()
+            .map(|body| [body.position.0, body.position.1, body.position.2])
+            .collect()
+    }
+
+    fn velocities(&self) -> Vec<[f64; 3]> {
+        self.bodies.iter()
+            .map(|body| [body.velocity.0, body.velocity.1, body.velocity.2])
+            .collect()
+    }
 }
@@ -0,0 +1,226 @@
+//! Recording of orbits for plotting and external analysis.
+//!
+//! A `Trajectory` is an `Observer` that keeps a `Snapshot` of every state it
+//! sees, then writes them one row per body and sample, as CSV or as JSON
+//! Lines:
+//!
+//! ```text
+//! body,step,time,x,y,z,vx,vy,vz,energy
+//! ```
+//!
+//! `energy` is the energy of the whole system at that step, repeated on each
+//! body's row.
+
+use std::io::{self, Write};
+
+use crate::integrator::Integrator;
+use crate::observer::{advance_observed, Observer, Snapshot};
+use crate::NBodySimulator;
+
+/// Samples of a simulation's state, taken every few steps.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trajectory {
+    names: Vec<String>,
+    dt: f64,
+    samples: Vec<Snapshot>,
+}
+
+impl Trajectory {
+    /// Empty trajectory of a system whose bodies are called `names`, stepped
+    /// with time-steps of length `dt`.
+    ///
+    /// Bodies beyond the end of `names`, or with an empty name, are called by
+    /// their index.
+    pub fn new(names: Vec<String>, dt: f64) -> Trajectory {
+        Trajectory { names, dt, samples: Vec::new() }
+    }
+
+    /// Runs `sim` for `steps` steps of `dt` with `integrator`, sampling the
+    /// initial state and every `every`th step.
+    ///
+    /// Names are taken from `names`; implementations do not keep them.
+    pub fn record<S: NBodySimulator>(
+        sim: &mut S,
+        names: Vec<String>,
+        integrator: Integrator,
+        steps: usize,
+        dt: f64,
+        every: usize,
+    ) -> Trajectory {
+        let mut trajectory = Trajectory::new(names, dt);
+        advance_observed(sim, integrator, steps, dt, every, &mut trajectory);
+        trajectory
+    }
+
+    pub fn samples(&self) -> &[Snapshot] {
+        &self.samples
+    }
+
+    fn name(&self, body: usize) -> String {
+        match self.names.get(body) {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => format!("body {}", body),
+        }
+    }
+
+    /// Rows of `(name, sample, body index)`, sample by sample.
+    fn rows(&self) -> impl Iterator<Item = (String, &Snapshot, usize)> {
+        self.samples.iter().flat_map(move |sample| {
+            (0..sample.positions.len()).map(move |body| (self.name(body), sample, body))
+        })
+    }
+
+    /// Writes a header and one line per body and sample.
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "body,step,time,x,y,z,vx,vy,vz,energy")?;
+        for (name, sample, body) in self.rows() {
+            let [x, y, z] = sample.positions[body];
+            let [vx, vy, vz] = sample.velocities[body];
+            writeln!(
+                writer,
+                "{},{},{:?},{:?},{:?},{:?},{:?},{:?},{:?},{:?}",
+                csv_field(&name),
+                sample.step,
+                sample.step as f64 * self.dt,
+                x, y, z, vx, vy, vz,
+                sample.energy,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes one JSON object per body and sample, each on its own line.
+    ///
+    /// Non-finite numbers, which JSON cannot represent, are written as `null`.
+    pub fn write_json_lines<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (name, sample, body) in self.rows() {
+            let [x, y, z] = sample.positions[body];
+            let [vx, vy, vz] = sample.velocities[body];
+            writeln!(
+                writer,
+                "{{\"body\":{},\"step\":{},\"time\":{},\"x\":{},\"y\":{},\"z\":{},\"vx\":{},\"vy\":{},\"vz\":{},\"energy\":{}}}",
+                json_string(&name),
+                sample.step,
+                json_number(sample.step as f64 * self.dt),
+                json_number(x), json_number(y), json_number(z),
+                json_number(vx), json_number(vy), json_number(vz),
+                json_number(sample.energy),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: NBodySimulator> Observer<S> for Trajectory {
+    fn observe(&mut self, step: usize, sim: &S) {
+        self.samples.push(Snapshot::of(sim, step));
+    }
+}
+
+/// Quotes `field` if it contains a separator, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_number(number: f64) -> String {
+    if number.is_finite() {
+        format!("{:?}", number)
+    } else {
+        "null".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{biffle, body, BodyState};
+
+    fn jovian_trajectory() -> Trajectory {
+        let names = body::starting_state().into_iter().map(|body| body.name).collect();
+        let mut sim = biffle::Simulation::starting_state();
+        sim.offset_momentum();
+        Trajectory::record(&mut sim, names, Integrator::SemiImplicitEuler, 10, 0.01, 5)
+    }
+
+    #[test]
+    fn csv() {
+        let mut csv = Vec::new();
+        jovian_trajectory().write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        // A header, then 5 bodies at steps 0, 5 and 10.
+        assert_eq!(lines.len(), 1 + 3 * 5);
+        assert_eq!(lines[0], "body,step,time,x,y,z,vx,vy,vz,energy");
+        assert!(lines[1].starts_with("Sun,0,0.0,0.0,0.0,0.0,"), "{}", lines[1]);
+        assert!(lines[15].starts_with("Neptune,10,0.1,"), "{}", lines[15]);
+
+        // Numbers are written exactly.
+        let fields: Vec<&str> = lines[7].split(',').collect();
+        assert_eq!(fields[0], "Jupiter");
+        let mut sim = biffle::Simulation::starting_state();
+        sim.offset_momentum();
+        sim.advance(5, 0.01);
+        assert_eq!(fields[3].parse::<f64>().unwrap(), sim.positions()[1][0]);
+        assert_eq!(fields[9].parse::<f64>().unwrap(), sim.energy());
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut json = Vec::new();
+        jovian_trajectory().write_json_lines(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+
+        assert_eq!(lines.len(), 3 * 5);
+        assert!(lines[0].starts_with("{\"body\":\"Sun\",\"step\":0,\"time\":0.0,\"x\":0.0,"), "{}", lines[0]);
+        assert!(lines[14].starts_with("{\"body\":\"Neptune\",\"step\":10,"), "{}", lines[14]);
+        assert!(lines.iter().all(|line| line.ends_with('}')));
+    }
+
+    #[test]
+    fn awkward_names_and_numbers() {
+        let bodies = vec![
+            BodyState { name: "Comet \"A, B\"".to_string(), position: [f64::NAN, 0., 1e-300], velocity: [0.; 3], mass: 1. },
+            BodyState { name: String::new(), position: [1., 0., 0.], velocity: [0.; 3], mass: 1. },
+        ];
+        let sim = biffle::Simulation::from_bodies(&bodies);
+        let names = bodies.iter().map(|body| body.name.clone()).collect();
+        let mut trajectory = Trajectory::new(names, 0.01);
+        Observer::observe(&mut trajectory, 0, &sim);
+
+        let mut csv = Vec::new();
+        trajectory.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.contains("\n\"Comet \"\"A, B\"\"\",0,0.0,NaN,0.0,1e-300,"), "{}", csv);
+        assert!(csv.contains("\nbody 1,0,0.0,1.0,"), "{}", csv);
+
+        let mut json = Vec::new();
+        trajectory.write_json_lines(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"body\":\"Comet \\\"A, B\\\"\",\"step\":0,\"time\":0.0,\"x\":null,\"y\":0.0,\"z\":1e-300,"), "{}", json);
+        assert!(json.contains("{\"body\":\"body 1\","), "{}", json);
+    }
+}